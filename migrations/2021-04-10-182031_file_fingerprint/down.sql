@@ -0,0 +1,3 @@
+ALTER TABLE photos DROP COLUMN file_size;
+ALTER TABLE photos DROP COLUMN file_mtime;
+ALTER TABLE photos DROP COLUMN file_inode;
@@ -0,0 +1,5 @@
+-- Remember size, modification time and inode of the file for each
+-- photo, so findphotos can skip files that has not changed.
+ALTER TABLE photos ADD COLUMN file_size BIGINT;
+ALTER TABLE photos ADD COLUMN file_mtime TIMESTAMP;
+ALTER TABLE photos ADD COLUMN file_inode BIGINT;
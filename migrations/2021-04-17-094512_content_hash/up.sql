@@ -0,0 +1,5 @@
+-- A hash of the file contents, to recognize photos that are moved
+-- or renamed.
+ALTER TABLE photos ADD COLUMN content_hash VARCHAR;
+
+CREATE INDEX photos_content_hash_idx ON photos (content_hash);
@@ -0,0 +1,3 @@
+-- Photos whose file has disappeared can be marked as missing, to hide
+-- them without losing their metadata.
+ALTER TABLE photos ADD COLUMN is_missing BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,13 @@
+-- A raw file is stored as a photo that refers to the jpeg made from
+-- it, and only the jpeg is shown.  A raw file without a jpeg is
+-- shown as a photo of its own.
+ALTER TABLE photos ADD COLUMN raw_of INTEGER REFERENCES photos (id);
+CREATE INDEX photos_raw_of_idx ON photos (raw_of);
+
+UPDATE photos AS r SET raw_of = (
+  SELECT min(j.id) FROM photos AS j
+  WHERE j.path ~* '\.jpe?g$'
+  AND regexp_replace(j.path, '\.[^./]*$', '')
+    = regexp_replace(r.path, '\.[^./]*$', '')
+)
+WHERE r.path ~* '\.(arw|cr2|cr3|dng|nef|orf|raf|rw2)$';
@@ -0,0 +1,3 @@
+-- A perceptual hash of the image, to find duplicates that are not
+-- identical files.
+ALTER TABLE photos ADD COLUMN dhash BIGINT;
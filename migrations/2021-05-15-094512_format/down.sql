@@ -0,0 +1,2 @@
+ALTER TABLE photos DROP COLUMN mime_type;
+ALTER TABLE photos DROP COLUMN format;
@@ -0,0 +1,4 @@
+-- The detected file format (as a canonical file name extension) and
+-- mime type of the file.
+ALTER TABLE photos ADD COLUMN format VARCHAR;
+ALTER TABLE photos ADD COLUMN mime_type VARCHAR;
@@ -0,0 +1,2 @@
+-- The length of a video, in milliseconds.
+ALTER TABLE photos ADD COLUMN duration INTEGER;
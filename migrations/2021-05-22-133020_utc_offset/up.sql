@@ -0,0 +1,3 @@
+-- The offset of photos.date from UTC, in seconds east of UTC, when
+-- known.  The date itself is the local time where the photo was taken.
+ALTER TABLE photos ADD COLUMN utc_offset INTEGER;
@@ -0,0 +1,3 @@
+-- Seconds that the date of a photo is shifted from the time in the
+-- file, to correct a camera clock that was wrong.
+ALTER TABLE photos ADD COLUMN time_shift INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+ALTER TABLE photos DROP COLUMN flash;
+ALTER TABLE photos DROP COLUMN focal_length;
+ALTER TABLE photos DROP COLUMN iso;
+ALTER TABLE photos DROP COLUMN f_number;
+ALTER TABLE photos DROP COLUMN exposure_time;
+ALTER TABLE photos DROP COLUMN lens_id;
+DROP TABLE lenses;
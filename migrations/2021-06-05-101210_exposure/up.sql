@@ -0,0 +1,17 @@
+CREATE TABLE lenses (
+  id           SERIAL PRIMARY KEY,
+  manufacturer VARCHAR NOT NULL,
+  model        VARCHAR NOT NULL
+);
+
+CREATE UNIQUE INDEX lenses_idx ON lenses (manufacturer, model);
+
+ALTER TABLE photos ADD COLUMN lens_id INTEGER REFERENCES lenses (id);
+-- Exposure time in seconds.
+ALTER TABLE photos ADD COLUMN exposure_time REAL;
+ALTER TABLE photos ADD COLUMN f_number REAL;
+ALTER TABLE photos ADD COLUMN iso INTEGER;
+-- Focal length in mm.
+ALTER TABLE photos ADD COLUMN focal_length REAL;
+-- True if the flash fired.
+ALTER TABLE photos ADD COLUMN flash BOOLEAN;
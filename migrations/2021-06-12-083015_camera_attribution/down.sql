@@ -0,0 +1 @@
+ALTER TABLE cameras DROP COLUMN attribution_id;
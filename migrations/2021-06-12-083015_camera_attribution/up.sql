@@ -0,0 +1,3 @@
+-- Default attribution for photos from cameras that don't write one.
+ALTER TABLE cameras ADD COLUMN attribution_id
+INTEGER REFERENCES attributions (id);
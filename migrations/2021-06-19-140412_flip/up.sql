@@ -0,0 +1,2 @@
+-- True if the image should be mirrored (horizontally, before rotation).
+ALTER TABLE photos ADD COLUMN flip BOOLEAN NOT NULL DEFAULT false;
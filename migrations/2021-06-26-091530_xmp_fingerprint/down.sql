@@ -0,0 +1,2 @@
+ALTER TABLE photos DROP COLUMN xmp_size;
+ALTER TABLE photos DROP COLUMN xmp_mtime;
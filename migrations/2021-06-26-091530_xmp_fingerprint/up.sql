@@ -0,0 +1,4 @@
+-- Size and modification time of the xmp sidecar when it was last
+-- imported, so an incremental findphotos can tell if it has changed.
+ALTER TABLE photos ADD COLUMN xmp_size BIGINT;
+ALTER TABLE photos ADD COLUMN xmp_mtime TIMESTAMP;
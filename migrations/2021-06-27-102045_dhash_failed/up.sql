@@ -0,0 +1,3 @@
+-- True if no perceptual hash could be made from the file as it is,
+-- so an incremental findphotos need not try again.
+ALTER TABLE photos ADD COLUMN dhash_failed BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,82 @@
+use super::result::Error;
+use crate::models::{Attribution, Camera};
+use crate::schema::attributions::dsl as a;
+use crate::schema::cameras::dsl as c;
+use crate::schema::photos::dsl as p;
+use crate::DbOpt;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::update;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct CameraAttribution {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    /// Remove the default attribution of the camera.
+    #[structopt(long, requires = "camera", conflicts_with = "name")]
+    clear: bool,
+
+    /// The camera model.  Without it, all cameras are listed.
+    camera: Option<String>,
+
+    /// The name to attribute photos from the camera to.
+    name: Option<String>,
+}
+
+impl CameraAttribution {
+    pub fn run(&self) -> Result<(), Error> {
+        let db = self.db.connect()?;
+        let model = match &self.camera {
+            Some(model) => model,
+            None => {
+                for camera in c::cameras
+                    .order((c::manufacturer, c::model))
+                    .load::<Camera>(&db)?
+                {
+                    print_camera(&db, &camera)?;
+                }
+                return Ok(());
+            }
+        };
+        let camera = c::cameras
+            .filter(c::model.eq(model))
+            .first::<Camera>(&db)
+            .optional()?
+            .ok_or_else(|| Error::Other(format!("No camera {:?}", model)))?;
+        let camera = if self.clear {
+            update(c::cameras.find(camera.id))
+                .set(c::attribution_id.eq(None::<i32>))
+                .get_result(&db)?
+        } else if let Some(name) = &self.name {
+            let attribution = Attribution::get_or_create(&db, name)?;
+            let camera = update(c::cameras.find(camera.id))
+                .set(c::attribution_id.eq(attribution.id))
+                .get_result::<Camera>(&db)?;
+            // Existing photos are not read again by findphotos.
+            let n = update(
+                p::photos
+                    .filter(p::camera_id.eq(camera.id))
+                    .filter(p::attribution_id.is_null()),
+            )
+            .set(p::attribution_id.eq(attribution.id))
+            .execute(&db)?;
+            println!("Set attribution of {} existing photos.", n);
+            camera
+        } else {
+            camera
+        };
+        print_camera(&db, &camera)
+    }
+}
+
+fn print_camera(db: &PgConnection, camera: &Camera) -> Result<(), Error> {
+    let name: String = match camera.attribution_id {
+        Some(id) => a::attributions.find(id).select(a::name).first(db)?,
+        None => "-".into(),
+    };
+    println!("{} ({}): {}", camera.model, camera.manufacturer, name);
+    Ok(())
+}
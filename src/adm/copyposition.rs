@@ -0,0 +1,277 @@
+use super::result::Error;
+use crate::fetch_places::OverpassOpt;
+use crate::models::{Coord, Photo};
+use crate::schema::photos;
+use crate::schema::photos::dsl as p;
+use crate::schema::positions::dsl as pos;
+use crate::DbOpt;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use log::{info, warn};
+use std::ops::Range;
+use structopt::clap::ArgGroup;
+use structopt::StructOpt;
+
+/// Default max time between a photo and the photo its position is
+/// copied from, in seconds.
+pub const DEFAULT_WINDOW: i64 = 30 * 60;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+#[structopt(group = ArgGroup::with_name("selection").required(true))]
+pub struct CopyPositions {
+    #[structopt(flatten)]
+    db: DbOpt,
+    #[structopt(flatten)]
+    overpass: OverpassOpt,
+
+    /// Max seconds between a photo and the photo to copy from.
+    #[structopt(long, default_value = "1800")]
+    window: i64,
+
+    /// Set positions for all photos without one from this day.
+    #[structopt(long, group = "selection")]
+    day: Option<NaiveDate>,
+
+    /// Fetch places for each photo that gets a position.
+    #[structopt(long)]
+    fetch_places: bool,
+
+    /// Show what positions would be set, without changing anything.
+    #[structopt(long, short = "n")]
+    dry_run: bool,
+
+    /// Ids of photos to set positions for.
+    #[structopt(group = "selection")]
+    photos: Vec<i32>,
+}
+
+impl CopyPositions {
+    pub async fn run(&self) -> Result<(), Error> {
+        let pool = self.db.create_pool()?;
+        let db = pool.get()?;
+        let photos = match self.day {
+            Some(day) => unpositioned_on_day(&db, day)?,
+            None => p::photos
+                .filter(p::id.eq_any(&self.photos))
+                .filter(p::id.ne_all(pos::positions.select(pos::photo_id)))
+                .order((p::date, p::id))
+                .load(&db)?,
+        };
+        let found = find_matches(&db, photos, Duration::seconds(self.window))?;
+        for m in &found {
+            println!(
+                "#{}: {} <- #{} {} ({} s): {} {}",
+                m.photo.id,
+                m.photo.path,
+                m.source.id,
+                m.source.path,
+                m.distance().num_seconds(),
+                m.coord.x,
+                m.coord.y,
+            );
+        }
+        if self.dry_run {
+            println!("Would set positions for {} photos.", found.len());
+            return Ok(());
+        }
+        for m in &found {
+            set_position(&db, m.photo.id, &m.coord)?;
+        }
+        println!("Set positions for {} photos.", found.len());
+        if self.fetch_places {
+            for m in &found {
+                let id = m.photo.id;
+                if let Err(e) =
+                    self.overpass.update_image_places(&pool, id).await
+                {
+                    warn!("Failed to fetch places for #{}: {:?}", id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A photo without a position and the photo to copy a position from.
+pub struct Match {
+    pub photo: Photo,
+    pub source: Photo,
+    pub coord: Coord,
+}
+
+impl Match {
+    /// How much later the source photo is taken than the photo.
+    pub fn distance(&self) -> Duration {
+        match (self.source.utc_date(), self.photo.utc_date()) {
+            (Some(source), Some(photo)) => source - photo,
+            _ => Duration::zero(),
+        }
+    }
+}
+
+/// Get the photos without position taken on `day`, in date order.
+pub fn unpositioned_on_day(
+    db: &PgConnection,
+    day: NaiveDate,
+) -> Result<Vec<Photo>, Error> {
+    let day = day_range(day);
+    Ok(p::photos
+        .filter(p::date.ge(day.start))
+        .filter(p::date.lt(day.end))
+        .filter(p::id.ne_all(pos::positions.select(pos::photo_id)))
+        .order((p::date, p::id))
+        .load(db)?)
+}
+
+/// The local times of `day`.
+fn day_range(day: NaiveDate) -> Range<NaiveDateTime> {
+    let start = day.and_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+    start..start + Duration::days(1)
+}
+
+/// Find a photo to copy the position from for each of `photos`.
+///
+/// Photos that have no positioned photo within `window` are left out.
+pub fn find_matches(
+    db: &PgConnection,
+    photos: Vec<Photo>,
+    window: Duration,
+) -> Result<Vec<Match>, Error> {
+    let mut result = Vec::new();
+    for photo in photos {
+        if let Some((source, coord)) = nearest_positioned(db, &photo, window)?
+        {
+            result.push(Match {
+                photo,
+                source,
+                coord,
+            });
+        }
+    }
+    Ok(result)
+}
+
+/// Find the positioned photo taken nearest in time to `photo`.
+///
+/// The times are compared in UTC, see [`Photo::utc_date`], since
+/// the photos may be taken with cameras set to different timezones.
+pub fn nearest_positioned(
+    db: &PgConnection,
+    photo: &Photo,
+    window: Duration,
+) -> Result<Option<(Photo, Coord)>, Error> {
+    let (date, time) = match (photo.date, photo.utc_date()) {
+        (Some(date), Some(time)) => (date, time),
+        _ => return Ok(None),
+    };
+    // Utc offsets are at most 14 hours, so this gets all candidates.
+    let zone = Duration::hours(14);
+    let candidates = p::photos
+        .inner_join(pos::positions)
+        .filter(p::id.ne(photo.id))
+        .filter(p::date.ge(date - window - zone))
+        .filter(p::date.le(date + window + zone))
+        .select((photos::all_columns, (pos::latitude, pos::longitude)))
+        .load::<(Photo, Coord)>(db)?;
+    Ok(nearest(time, candidates, window))
+}
+
+/// The candidate taken nearest to `time`, in UTC, within `window`.
+fn nearest(
+    time: NaiveDateTime,
+    candidates: Vec<(Photo, Coord)>,
+    window: Duration,
+) -> Option<(Photo, Coord)> {
+    candidates
+        .into_iter()
+        .filter_map(|(source, coord)| {
+            let distance = (source.utc_date()? - time).num_seconds().abs();
+            if distance > window.num_seconds() {
+                return None;
+            }
+            Some((distance, source, coord))
+        })
+        .min_by_key(|(distance, source, _)| (*distance, source.id))
+        .map(|(_, source, coord)| (source, coord))
+}
+
+/// Set the position of a photo, replacing any existing position.
+pub fn set_position(
+    db: &PgConnection,
+    photo_id: i32,
+    coord: &Coord,
+) -> Result<(), Error> {
+    info!("Set position of #{} to {:?}", photo_id, coord);
+    let (lat, lng) = ((coord.x * 1e6) as i32, (coord.y * 1e6) as i32);
+    diesel::insert_into(pos::positions)
+        .values((
+            pos::photo_id.eq(photo_id),
+            pos::latitude.eq(lat),
+            pos::longitude.eq(lng),
+        ))
+        .on_conflict(pos::photo_id)
+        .do_update()
+        .set((pos::latitude.eq(lat), pos::longitude.eq(lng)))
+        .execute(db)?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn positioned(photo: Photo, x: f64) -> (Photo, Coord) {
+    (photo, Coord { x, y: 18.0 })
+}
+
+#[test]
+fn nearest_within_window_edges() {
+    let photo = Photo::mock(2021, 5, 16, 12, 0, 0);
+    let time = photo.utc_date().unwrap();
+    let window = Duration::minutes(30);
+    let at_edge = || positioned(Photo::mock(2021, 5, 16, 12, 30, 0), 59.1);
+    let (source, _) = nearest(time, vec![at_edge()], window).unwrap();
+    assert_eq!(source.date, at_edge().0.date);
+    let outside = vec![
+        positioned(Photo::mock(2021, 5, 16, 12, 30, 1), 59.2),
+        positioned(Photo::mock(2021, 5, 16, 11, 29, 59), 59.3),
+    ];
+    assert!(nearest(time, outside, window).is_none());
+}
+
+#[test]
+fn nearest_picks_closest() {
+    let photo = Photo::mock(2021, 5, 16, 12, 0, 0);
+    let time = photo.utc_date().unwrap();
+    let candidates = vec![
+        positioned(Photo::mock(2021, 5, 16, 11, 50, 0), 59.1),
+        positioned(Photo::mock(2021, 5, 16, 12, 5, 0), 59.2),
+        positioned(Photo::mock(2021, 5, 16, 12, 20, 0), 59.3),
+    ];
+    let (_, coord) = nearest(time, candidates, Duration::minutes(30)).unwrap();
+    assert_eq!(coord.x, 59.2);
+}
+
+#[test]
+fn nearest_compares_utc() {
+    // A phone set to UTC+2, and a camera set to UTC.
+    let mut photo = Photo::mock(2021, 5, 16, 12, 0, 0);
+    photo.utc_offset = Some(2 * 3600);
+    let mut same_time = Photo::mock(2021, 5, 16, 10, 5, 0);
+    same_time.utc_offset = Some(0);
+    let mut same_clock = Photo::mock(2021, 5, 16, 12, 1, 0);
+    same_clock.utc_offset = Some(0);
+    let candidates =
+        vec![positioned(same_clock, 59.1), positioned(same_time, 59.2)];
+    let time = photo.utc_date().unwrap();
+    let (_, coord) = nearest(time, candidates, Duration::minutes(30)).unwrap();
+    assert_eq!(coord.x, 59.2);
+}
+
+#[test]
+fn whole_day() {
+    let day = day_range(NaiveDate::from_ymd_opt(2021, 5, 16).unwrap());
+    assert!(day.contains(&Photo::mock(2021, 5, 16, 0, 0, 0).date.unwrap()));
+    assert!(day.contains(&Photo::mock(2021, 5, 16, 23, 59, 59).date.unwrap()));
+    assert!(!day.contains(&Photo::mock(2021, 5, 17, 0, 0, 0).date.unwrap()));
+    assert!(!day.contains(&Photo::mock(2021, 5, 15, 23, 59, 59).date.unwrap()));
+}
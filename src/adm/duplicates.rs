@@ -0,0 +1,183 @@
+use super::result::Error;
+use crate::models::Photo;
+use crate::DbOpt;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use std::collections::HashMap;
+use structopt::StructOpt;
+
+/// Default max number of differing bits for photos to be duplicates.
+pub const DEFAULT_DISTANCE: u32 = 4;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct Duplicates {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    /// Max number of differing bits in the perceptual hashes (0 - 64).
+    ///
+    /// Zero finds only photos that look identical, a higher number
+    /// finds photos that are more different.
+    #[structopt(long, short, default_value = "4")]
+    distance: u32,
+}
+
+impl Duplicates {
+    pub fn run(&self) -> Result<(), Error> {
+        let groups = find_duplicates(&self.db.connect()?, self.distance)?;
+        for group in &groups {
+            println!();
+            for photo in group {
+                println!(
+                    "#{}: {} {}x{} {} bytes, {}, grade {}",
+                    photo.id,
+                    photo.path,
+                    photo.width,
+                    photo.height,
+                    photo.file_size.unwrap_or_default(),
+                    photo.date.map_or("no date".into(), |d| d.to_string()),
+                    photo.grade.map_or("-".into(), |g| g.to_string()),
+                );
+            }
+        }
+        println!("Found {} groups of duplicates.", groups.len());
+        Ok(())
+    }
+}
+
+/// Find groups of photos with similar perceptual hashes.
+///
+/// Each photo is in the same group as any photo with a hash that
+/// differs by at most `distance` bits.  Note that two photos in a
+/// group may differ more, if there is a third photo between them.
+pub fn find_duplicates(
+    db: &PgConnection,
+    distance: u32,
+) -> Result<Vec<Vec<Photo>>, Error> {
+    use crate::schema::photos::dsl as p;
+    let photos = Photo::query(true)
+        .filter(p::dhash.is_not_null())
+        .order((p::date, p::id))
+        .load::<Photo>(db)?;
+    let hashes = photos
+        .iter()
+        .map(|photo| photo.dhash.unwrap_or_default() as u64)
+        .collect::<Vec<_>>();
+    let mut parents = group_similar(&hashes, distance);
+    let mut groups = Vec::<Vec<Photo>>::new();
+    let mut group_of = vec![None::<usize>; photos.len()];
+    for (i, photo) in photos.into_iter().enumerate() {
+        let r = root(&mut parents, i);
+        match group_of[r] {
+            Some(g) => groups[g].push(photo),
+            None => {
+                group_of[r] = Some(groups.len());
+                groups.push(vec![photo]);
+            }
+        }
+    }
+    groups.retain(|group| group.len() > 1);
+    Ok(groups)
+}
+
+/// Join the indexes of similar `hashes` in a union-find forest.
+///
+/// Rather than comparing all pairs, this uses multi-index hashing:
+/// The hashes are split in `distance + 1` parts, and two hashes that
+/// differ by at most `distance` bits must have at least one part in
+/// common, so only hashes with a common part are compared.
+/// Any two hashes differ by at most 64 bits, so from that distance on
+/// all hashes are similar.
+fn group_similar(hashes: &[u64], distance: u32) -> Vec<usize> {
+    if distance >= 64 {
+        return vec![0; hashes.len()];
+    }
+    let mut parents = (0..hashes.len()).collect::<Vec<_>>();
+    let join = |parents: &mut [usize], i: usize, j: usize| {
+        let (ri, rj) = (root(parents, i), root(parents, j));
+        parents[rj.max(ri)] = rj.min(ri);
+    };
+    // Identical hashes are joined directly, so they can be compared
+    // once.
+    let mut first_of = HashMap::<u64, usize>::new();
+    for (i, hash) in hashes.iter().enumerate() {
+        let first = *first_of.entry(*hash).or_insert(i);
+        join(&mut parents, first, i);
+    }
+    let unique = first_of.into_iter().collect::<Vec<_>>();
+    let parts = distance + 1;
+    for part in 0..parts {
+        let (start, end) = (part * 64 / parts, (part + 1) * 64 / parts);
+        let mask = (u64::MAX >> (64 - (end - start))) << start;
+        let mut buckets = HashMap::<u64, Vec<(u64, usize)>>::new();
+        for &(hash, i) in &unique {
+            buckets.entry(hash & mask).or_default().push((hash, i));
+        }
+        for bucket in buckets.values() {
+            for (n, (a, i)) in bucket.iter().enumerate() {
+                for (b, j) in &bucket[n + 1..] {
+                    if (a ^ b).count_ones() <= distance {
+                        join(&mut parents, *i, *j);
+                    }
+                }
+            }
+        }
+    }
+    parents
+}
+
+/// Find the root of `i` in a union-find forest.
+fn root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+#[test]
+fn group_similar_as_all_pairs() {
+    let hashes = [
+        0x0000_0000_0000_0000,
+        0x0000_0000_0000_0007,
+        0x8000_0000_0000_0001,
+        0xffff_0000_ffff_0000,
+        0xffff_0000_ffff_0000,
+        0xfff0_0000_ffff_000f,
+        0x0123_4567_89ab_cdef,
+        0x0123_4567_89ab_cdee,
+    ];
+    for distance in 0..10 {
+        let mut parents = group_similar(&hashes, distance);
+        let mut expected = (0..hashes.len()).collect::<Vec<_>>();
+        for (i, a) in hashes.iter().enumerate() {
+            for (j, b) in hashes.iter().enumerate().skip(i + 1) {
+                if (a ^ b).count_ones() <= distance {
+                    let (ri, rj) =
+                        (root(&mut expected, i), root(&mut expected, j));
+                    expected[rj.max(ri)] = rj.min(ri);
+                }
+            }
+        }
+        for i in 0..hashes.len() {
+            assert_eq!(
+                root(&mut parents, i),
+                root(&mut expected, i),
+                "#{} at distance {}",
+                i,
+                distance,
+            );
+        }
+    }
+}
+
+#[test]
+fn group_similar_at_max_distance() {
+    let hashes = [0, u64::MAX];
+    let mut parents = group_similar(&hashes, 63);
+    assert_ne!(root(&mut parents, 0), root(&mut parents, 1));
+    let mut parents = group_similar(&hashes, 64);
+    assert_eq!(root(&mut parents, 0), root(&mut parents, 1));
+    assert_eq!(group_similar(&hashes, 65), [0, 0]);
+}
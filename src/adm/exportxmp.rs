@@ -0,0 +1,94 @@
+use super::result::Error;
+use crate::models::{Photo, Place};
+use crate::xmp::XmpData;
+use crate::DbOpt;
+use diesel::prelude::*;
+use log::debug;
+use std::fs::{self, create_dir_all};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct ExportXmp {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    /// Directory to write the sidecars in.
+    ///
+    /// The sidecar for a photo is written to the same path under
+    /// this directory as the photo has under the image root, with
+    /// .xmp added to the file name.
+    #[structopt(long, short)]
+    out: PathBuf,
+}
+
+impl ExportXmp {
+    pub fn run(&self) -> Result<(), Error> {
+        let db = self.db.connect()?;
+        let photos = Photo::query(true)
+            .order(crate::schema::photos::dsl::path)
+            .load::<Photo>(&db)?;
+        let (mut written, mut unchanged) = (0, 0);
+        for photo in &photos {
+            let xml = photo_xmp(&db, photo)?.to_xml();
+            let path = self.out.join(format!("{}.xmp", photo.path));
+            if fs::read_to_string(&path).ok().as_ref() == Some(&xml) {
+                debug!("Sidecar {:?} is up to date", path);
+                unchanged += 1;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            fs::write(&path, xml).map_err(|e| Error::in_file(&e, &path))?;
+            debug!("Wrote {:?}", path);
+            written += 1;
+        }
+        println!(
+            "Wrote {} xmp sidecars, {} were already up to date.",
+            written, unchanged,
+        );
+        Ok(())
+    }
+}
+
+/// Get the curated metadata for a photo.
+fn photo_xmp(db: &PgConnection, photo: &Photo) -> Result<XmpData, Error> {
+    let places = photo.load_places(db)?;
+    let mut xmp = XmpData {
+        keywords: photo
+            .load_tags(db)?
+            .into_iter()
+            .map(|t| t.tag_name)
+            .collect(),
+        people: photo
+            .load_people(db)?
+            .into_iter()
+            .map(|p| p.person_name)
+            .collect(),
+        position: photo.load_position(db).map(|c| (c.x, c.y)),
+        // Places are ordered most specific first.
+        location: places
+            .first()
+            .filter(|p| !matches!(p.osm_level, Some(l) if l <= 10))
+            .map(|p| p.place_name.clone()),
+        city: place_in_levels(&places, 7, 10),
+        state: place_in_levels(&places, 3, 6),
+        country: place_in_levels(&places, 2, 2),
+        creator: photo.load_attribution(db),
+        ..XmpData::default()
+    };
+    xmp.set_grade(photo.grade);
+    Ok(xmp)
+}
+
+/// The name of the least specific place with an osm admin level
+/// from `min` to `max`.
+fn place_in_levels(places: &[Place], min: i16, max: i16) -> Option<String> {
+    places
+        .iter()
+        .filter(|p| matches!(p.osm_level, Some(l) if l >= min && l <= max))
+        .min_by_key(|p| p.osm_level)
+        .map(|p| p.place_name.clone())
+}
@@ -1,13 +1,24 @@
+use super::orphans::find_orphans;
 use super::result::Error;
-use crate::models::{Camera, Modification, Photo};
+use crate::models::{
+    Attribution, Camera, Lens, Modification, Person, Photo, PhotoPerson,
+    PhotoTag, Tag,
+};
 use crate::myexif::ExifData;
-use crate::photosdir::PhotosDir;
+use crate::photosdir::{
+    is_raw, jpeg_siblings, raw_siblings, FileFormat, Fingerprint, FoundFile,
+    PhotosDir,
+};
+use crate::xmp::XmpData;
 use crate::{DbOpt, DirOpt};
 use diesel::insert_into;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -18,72 +29,435 @@ pub struct Findphotos {
     #[structopt(flatten)]
     photos: DirOpt,
 
+    /// Only read files that are new or changed since the last run.
+    ///
+    /// A file is considered unchanged if its size, modification time
+    /// and inode are the same as when it was last read.
+    #[structopt(long, short)]
+    incremental: bool,
+
+    #[structopt(flatten)]
+    save: SaveOpt,
+
+    /// After the crawl, list known photos whose file is gone.
+    ///
+    /// See the orphans subcommand for how to handle them.
+    #[structopt(long)]
+    orphans: bool,
+
+    /// Number of threads for reading directories and files.
+    ///
+    /// Defaults to the number of CPUs.  A higher number may be useful
+    /// when the photos are on a network file system.
+    #[structopt(long, short)]
+    jobs: Option<usize>,
+
+    /// Max number of photos to save in each database transaction.
+    #[structopt(long, default_value = "100")]
+    batch_size: usize,
+
     /// Base directory to search in (relative to the image root).
     base: Vec<String>,
 }
 
+/// Options for how found files are saved.
+#[derive(Clone, Copy, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct SaveOpt {
+    /// Relink moved photos instead of creating duplicates.
+    ///
+    /// When a new file has the same content as a known photo whose
+    /// file is gone, the known photo is moved to the new path, keeping
+    /// its tags, people, places, position and grade.
+    /// Only photos with a stored content hash can be relinked.
+    #[structopt(long)]
+    relink: bool,
+
+    /// Import keywords, people, rating and creator from xmp.
+    ///
+    /// Xmp is read from sidecar files (IMG_4711.JPG.xmp or
+    /// IMG_4711.xmp) and from xmp embedded in jpeg files.
+    /// Keywords are added as tags and people as people, nothing is
+    /// removed.  A rating of 1 to 5 stars is stored as grade 20 to
+    /// 100, and a rejected photo as grade 0.  The creator is used as
+    /// attribution, before the exif artist.
+    /// The xmp of a raw file is imported to its jpeg.
+    /// With --incremental, xmp is only read for changed images and
+    /// images with a changed sidecar.
+    #[structopt(long)]
+    pub xmp: bool,
+
+    /// Let the xmp rating replace a different grade in the database.
+    ///
+    /// By default, the grade in the database is kept.
+    #[structopt(long, requires = "xmp")]
+    xmp_wins: bool,
+}
+
 impl Findphotos {
     pub fn run(&self) -> Result<(), Error> {
         let pd = PhotosDir::new(&self.photos.photos_dir);
         let db = self.db.connect()?;
+        let known = if self.incremental {
+            load_fingerprints(&db)?
+        } else {
+            HashMap::new()
+        };
+        let mut summary = Summary::default();
         if !self.base.is_empty() {
             for base in &self.base {
-                crawl(&db, &pd, Path::new(base)).map_err(|e| {
-                    Error::Other(format!("Failed to crawl {}: {}", base, e))
-                })?;
+                self.crawl(&db, &pd, Path::new(base), &known, &mut summary)
+                    .map_err(|e| {
+                        Error::Other(format!(
+                            "Failed to crawl {}: {}",
+                            base, e,
+                        ))
+                    })?;
             }
         } else {
-            crawl(&db, &pd, Path::new("")).map_err(|e| {
-                Error::Other(format!("Failed to crawl: {}", e))
-            })?;
+            self.crawl(&db, &pd, Path::new(""), &known, &mut summary)
+                .map_err(|e| {
+                    Error::Other(format!("Failed to crawl: {}", e))
+                })?;
+        }
+        for line in &summary.relinked {
+            println!("Relinked {}", line);
+        }
+        println!("{}", summary);
+        if self.orphans {
+            let orphans = find_orphans(&db, &pd, &self.base)?;
+            for photo in &orphans {
+                println!("Orphan #{}: {}", photo.id, photo.path);
+            }
+            println!("Found {} photos with missing files.", orphans.len());
+        }
+        Ok(())
+    }
+
+    /// Find and save photos in `only_in`.
+    ///
+    /// Files are read in worker threads, and saved in the current
+    /// thread, one transaction per batch.
+    fn crawl(
+        &self,
+        db: &PgConnection,
+        photos: &PhotosDir,
+        only_in: &Path,
+        known: &HashMap<String, Known>,
+        summary: &mut Summary,
+    ) -> Result<(), Error> {
+        let skipped = AtomicU32::new(0);
+        let unreadable = AtomicU32::new(0);
+        let load = |file: &FoundFile| {
+            let unchanged = known.get(&file.path).is_some_and(|known| {
+                known.file == file.fingerprint
+                    && (!self.save.xmp
+                        || known.xmp == file.sidecar_fingerprint())
+            });
+            if unchanged {
+                debug!("Skipping unchanged {}", file.path);
+                skipped.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            match LoadedFile::read(file, self.save.xmp) {
+                Ok(Some(loaded)) => Some(loaded),
+                Ok(None) => {
+                    debug!("{:?} is no pic.", file.path);
+                    None
+                }
+                Err(e) => {
+                    warn!("Failed to read {}: {}", file.path, e);
+                    unreadable.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+            }
+        };
+        let mut raws = Vec::new();
+        let mut failure = None;
+        photos.find_files(
+            only_in,
+            self.jobs.unwrap_or(0),
+            self.batch_size,
+            &load,
+            &mut |batch| {
+                let (raw, batch): (Vec<_>, Vec<_>) =
+                    batch.into_iter().partition(|file| is_raw(&file.path));
+                raws.extend(raw);
+                if let Err(e) = self.save(db, photos, &batch, summary) {
+                    failure.get_or_insert(e);
+                }
+            },
+        )?;
+        // Raw files are saved last, so their jpeg files are known.
+        for batch in raws.chunks(self.batch_size.max(1)) {
+            if let Err(e) = self.save(db, photos, batch, summary) {
+                failure.get_or_insert(e);
+            }
+        }
+        summary.skipped += skipped.into_inner();
+        summary.failed += unreadable.into_inner();
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Save a batch of files, and count the results in `summary`.
+    fn save(
+        &self,
+        db: &PgConnection,
+        photos: &PhotosDir,
+        batch: &[LoadedFile],
+        summary: &mut Summary,
+    ) -> Result<(), Error> {
+        let results = match save_batch(db, photos, batch, self.save) {
+            Ok(results) => results,
+            Err(e) => {
+                summary.failed += batch.len() as u32;
+                return Err(e);
+            }
+        };
+        for (file, result) in batch.iter().zip(results) {
+            match result {
+                Ok(saved) => {
+                    debug!("Saved photo {}", file.path);
+                    summary.count(saved, &file.path);
+                }
+                Err(e) => {
+                    warn!("Failed to save photo {}: {:?}", file.path, e);
+                    summary.failed += 1;
+                }
+            }
         }
         Ok(())
     }
 }
 
-fn crawl(
+/// A file that is read and ready to be saved in the database.
+pub struct LoadedFile {
+    pub path: String,
+    fingerprint: Fingerprint,
+    hash: String,
+    dhash: Option<i64>,
+    /// True if the file should have a perceptual hash, but could not.
+    dhash_failed: bool,
+    format: Option<FileFormat>,
+    /// For a video, the length in milliseconds.
+    duration: Option<i32>,
+    pub exif: ExifData,
+    xmp: Option<XmpData>,
+    /// The sidecar of the file, if any, when xmp is read.
+    sidecar: Option<Fingerprint>,
+}
+
+impl LoadedFile {
+    /// Read metadata and content hash of a file, and xmp if `xmp`.
+    ///
+    /// Returns None if the file is neither an image nor a video.
+    pub fn read(
+        file: &FoundFile,
+        xmp: bool,
+    ) -> std::io::Result<Option<LoadedFile>> {
+        let video = file.load_video()?;
+        let exif = match (&video, file.load_meta()) {
+            (Some(video), _) => ExifData::for_video(video),
+            (None, Some(exif)) => exif,
+            (None, None) => return Ok(None),
+        };
+        let dhash = if is_raw(&file.path) || video.is_some() {
+            None
+        } else {
+            Some(file.perceptual_hash().map_err(|e| {
+                warn!("No perceptual hash for {}: {}", file.path, e);
+            }))
+        };
+        Ok(Some(LoadedFile {
+            path: file.path.clone(),
+            fingerprint: file.fingerprint.clone(),
+            hash: file.content_hash()?,
+            dhash: dhash.and_then(Result::ok).map(|dhash| dhash as i64),
+            dhash_failed: matches!(dhash, Some(Err(()))),
+            format: file.file_format()?,
+            duration: video
+                .and_then(|v| v.duration)
+                .and_then(|d| d.try_into().ok()),
+            exif,
+            xmp: if xmp { file.load_xmp() } else { None },
+            sidecar: if xmp {
+                file.sidecar_fingerprint()
+            } else {
+                None
+            },
+        }))
+    }
+}
+
+/// What [`save_photo`] did with a file.
+pub enum Saved {
+    Created,
+    Updated,
+    Unchanged,
+    /// An existing photo was moved from the given path.
+    Relinked(String),
+}
+
+impl Saved {
+    /// This, or updated if this was unchanged.
+    fn or_updated(self) -> Saved {
+        match self {
+            Saved::Unchanged => Saved::Updated,
+            saved => saved,
+        }
+    }
+}
+
+/// Counts of what happened to the files found in a crawl.
+#[derive(Debug, Default)]
+struct Summary {
+    created: u32,
+    updated: u32,
+    unchanged: u32,
+    skipped: u32,
+    failed: u32,
+    relinked: Vec<String>,
+}
+
+impl Summary {
+    fn count(&mut self, saved: Saved, path: &str) {
+        match saved {
+            Saved::Created => self.created += 1,
+            Saved::Updated => self.updated += 1,
+            Saved::Unchanged => self.unchanged += 1,
+            Saved::Relinked(from) => {
+                self.relinked.push(format!("{} -> {}", from, path))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, out: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            out,
+            "Found {} new and {} updated photos, {} unchanged, \
+             {} relinked. \
+             Skipped {} files with known fingerprint, {} failed.",
+            self.created,
+            self.updated,
+            self.unchanged,
+            self.relinked.len(),
+            self.skipped,
+            self.failed,
+        )
+    }
+}
+
+/// The fingerprints of a file known in the database.
+struct Known {
+    file: Fingerprint,
+    /// The xmp sidecar, as last imported.
+    xmp: Option<Fingerprint>,
+}
+
+/// Get the fingerprints of all files known in the database.
+///
+/// Photos without a perceptual hash or a known format are not
+/// included, so that they get those even in an incremental crawl.
+/// Raw files and videos never have a perceptual hash, and photos
+/// that failed to get one are not tried again until they change.
+fn load_fingerprints(
+    db: &PgConnection,
+) -> Result<HashMap<String, Known>, Error> {
+    use crate::schema::photos::dsl as p;
+    Ok(p::photos
+        .filter(p::is_missing.eq(false))
+        .filter(p::format.is_not_null())
+        .load::<Photo>(db)?
+        .into_iter()
+        .filter(|photo| {
+            photo.dhash.is_some()
+                || photo.dhash_failed
+                || photo.is_video()
+                || is_raw(&photo.path)
+        })
+        .filter_map(|photo| {
+            let file = photo.fingerprint()?;
+            let xmp = photo.xmp_fingerprint();
+            Some((photo.path, Known { file, xmp }))
+        })
+        .collect())
+}
+
+/// Save a batch of files in one transaction.
+///
+/// Each photo is saved in a savepoint of its own, so one failure does
+/// not abort the entire batch.
+fn save_batch(
     db: &PgConnection,
     photos: &PhotosDir,
-    only_in: &Path,
-) -> Result<(), Error> {
-    photos.find_files(
-        only_in,
-        &|path, exif| match save_photo(db, path, exif) {
-            Ok(()) => debug!("Saved photo {}", path),
-            Err(e) => warn!("Failed to save photo {}: {:?}", path, e),
-        },
-    )?;
-    Ok(())
+    batch: &[LoadedFile],
+    opt: SaveOpt,
+) -> Result<Vec<Result<Saved, Error>>, Error> {
+    db.transaction(|| {
+        Ok(batch
+            .iter()
+            .map(|file| {
+                db.transaction(|| {
+                    if is_raw(&file.path) {
+                        save_raw(db, photos, file, opt)
+                    } else {
+                        save_photo(db, photos, file, opt)
+                            .map(|(_photo, saved)| saved)
+                    }
+                })
+            })
+            .collect())
+    })
 }
 
-fn save_photo(
+/// Save a photo in the database, creating or updating it as needed.
+///
+/// The caller is responsible for any transaction.
+pub fn save_photo(
     db: &PgConnection,
-    file_path: &str,
-    exif: &ExifData,
-) -> Result<(), Error> {
-    let width = exif.width.ok_or(Error::MissingWidth)?;
-    let height = exif.height.ok_or(Error::MissingHeight)?;
-    let photo = match Photo::create_or_set_basics(
+    photos: &PhotosDir,
+    file: &LoadedFile,
+    opt: SaveOpt,
+) -> Result<(Photo, Saved), Error> {
+    let (file_path, exif) = (file.path.as_str(), &file.exif);
+    let moved_from = if opt.relink {
+        relink_moved(db, photos, file_path, &file.hash)?
+    } else {
+        None
+    };
+    let (photo, mut result) = save_basics(db, file)?;
+    if let Some(from) = moved_from {
+        result = Saved::Relinked(from);
+    }
+    let photo = photo.set_file_info(
         db,
-        file_path,
-        width as i32,
-        height as i32,
-        exif.date(),
-        exif.rotation()?,
-        find_camera(db, exif)?,
-    )? {
-        Modification::Created(photo) => {
-            info!("Created #{}, {}", photo.id, photo.path);
-            photo
-        }
-        Modification::Updated(photo) => {
-            info!("Modified {:?}", photo);
-            photo
-        }
-        Modification::Unchanged(photo) => {
-            debug!("No change for {:?}", photo);
-            photo
-        }
+        &file.fingerprint,
+        &file.hash,
+        file.dhash,
+        file.format,
+        file.duration,
+    )?;
+    let photo = photo.set_dhash_failed(db, file.dhash_failed)?;
+    let photo = if opt.xmp {
+        photo.set_xmp_fingerprint(db, file.sidecar.as_ref())?
+    } else {
+        photo
+    };
+    let photo = match &file.xmp {
+        Some(xmp) => match apply_xmp(db, photo, xmp, opt.xmp_wins)? {
+            Modification::Updated(photo) => {
+                if let Saved::Unchanged = result {
+                    result = Saved::Updated;
+                }
+                photo
+            }
+            Modification::Created(photo) | Modification::Unchanged(photo) => {
+                photo
+            }
+        },
+        None => photo,
     };
     if let Some((lat, long)) = exif.position() {
         debug!("Position for {} is {} {}", file_path, lat, long);
@@ -114,9 +488,273 @@ fn save_photo(
                 .expect("Insert image position");
         }
     }
+    if !is_raw(file_path) {
+        link_raws(db, &photo)?;
+    }
+    Ok((photo, result))
+}
+
+/// Link raw files that were saved before the jpeg `photo` to it.
+fn link_raws(db: &PgConnection, photo: &Photo) -> Result<(), Error> {
+    use crate::schema::photos::dsl as p;
+    let raws = p::photos
+        .filter(p::path.eq_any(raw_siblings(&photo.path)))
+        .filter(p::raw_of.is_null())
+        .load::<Photo>(db)?;
+    for raw in raws {
+        info!("Raw file {} belongs to #{}", raw.path, photo.id);
+        raw.set_raw_of(db, photo.id)?;
+    }
     Ok(())
 }
 
+/// Save a raw file, as belonging to the jpeg with the same base name.
+///
+/// A raw file whose jpeg is not known is saved as a photo of its own,
+/// until the jpeg is saved.
+pub fn save_raw(
+    db: &PgConnection,
+    photos: &PhotosDir,
+    file: &LoadedFile,
+    opt: SaveOpt,
+) -> Result<Saved, Error> {
+    use crate::schema::photos::dsl as p;
+    let jpeg = p::photos
+        .filter(p::path.eq_any(jpeg_siblings(&file.path)))
+        .filter(p::raw_of.is_null())
+        .select(p::id)
+        .order(p::id)
+        .first::<i32>(db)
+        .optional()?;
+    let jpeg = match jpeg {
+        Some(jpeg) => jpeg,
+        None => {
+            debug!("No jpeg found for raw file {}", file.path);
+            return save_photo(db, photos, file, opt)
+                .map(|(_photo, saved)| saved);
+        }
+    };
+    let moved_from = if opt.relink {
+        relink_moved(db, photos, &file.path, &file.hash)?
+    } else {
+        None
+    };
+    let (photo, result) = save_basics(db, file)?;
+    let photo = photo.set_file_info(
+        db,
+        &file.fingerprint,
+        &file.hash,
+        file.dhash,
+        file.format,
+        file.duration,
+    )?;
+    let photo = if opt.xmp {
+        photo.set_xmp_fingerprint(db, file.sidecar.as_ref())?
+    } else {
+        photo
+    };
+    let result = match (photo.set_raw_of(db, jpeg)?, result) {
+        (_, Saved::Created) => Saved::Created,
+        (Modification::Updated(photo), _) => {
+            info!("Raw file {} belongs to #{}", photo.path, jpeg);
+            Saved::Updated
+        }
+        (_, result) => result,
+    };
+    if let Some(xmp) = &file.xmp {
+        let jpeg = p::photos.find(jpeg).first::<Photo>(db)?;
+        if let Modification::Updated(jpeg) =
+            apply_xmp(db, jpeg, xmp, opt.xmp_wins)?
+        {
+            info!("Updated #{} from xmp of {}", jpeg.id, file.path);
+        }
+    }
+    Ok(moved_from.map(Saved::Relinked).unwrap_or(result))
+}
+
+/// Create or update the basic data of a photo from its exif data.
+fn save_basics(
+    db: &PgConnection,
+    file: &LoadedFile,
+) -> Result<(Photo, Saved), Error> {
+    let exif = &file.exif;
+    let width = exif.width.ok_or(Error::MissingWidth)?;
+    let height = exif.height.ok_or(Error::MissingHeight)?;
+    let camera = find_camera(db, exif)?;
+    let (rotation, flip) = exif.orientation()?;
+    let camera_attribution = camera.as_ref().and_then(|c| c.attribution_id);
+    let (photo, saved) = match Photo::create_or_set_basics(
+        db,
+        &file.path,
+        width as i32,
+        height as i32,
+        exif.date(),
+        exif.utc_offset(),
+        exif.date_is_from_gps(),
+        rotation,
+        flip,
+        camera,
+    )? {
+        Modification::Created(photo) => {
+            info!("Created #{}, {}", photo.id, photo.path);
+            (photo, Saved::Created)
+        }
+        Modification::Updated(photo) => {
+            info!("Modified {:?}", photo);
+            (photo, Saved::Updated)
+        }
+        Modification::Unchanged(photo) => {
+            debug!("No change for {:?}", photo);
+            (photo, Saved::Unchanged)
+        }
+    };
+    let lens = find_lens(db, exif)?;
+    let (photo, saved) =
+        match photo.set_exposure(db, &exif.exposure(), lens.as_ref())? {
+            Modification::Updated(photo) => {
+                info!("Exposure of #{}: {}", photo.id, photo.exposure());
+                (photo, saved.or_updated())
+            }
+            Modification::Created(photo) | Modification::Unchanged(photo) => {
+                (photo, saved)
+            }
+        };
+    // The camera default is only for photos without an attribution.
+    let attribution = match find_attribution(db, file)? {
+        Some(id) => Some(id),
+        None => camera_attribution.filter(|_| photo.attribution_id.is_none()),
+    };
+    Ok(match attribution {
+        Some(id) => match photo.set_attribution(db, id)? {
+            Modification::Updated(photo) => {
+                info!("Attribution of #{} set to {}", photo.id, id);
+                (photo, saved.or_updated())
+            }
+            Modification::Created(photo) | Modification::Unchanged(photo) => {
+                (photo, saved)
+            }
+        },
+        None => (photo, saved),
+    })
+}
+
+/// Find the attribution from the xmp creator or exif artist.
+fn find_attribution(
+    db: &PgConnection,
+    file: &LoadedFile,
+) -> Result<Option<i32>, Error> {
+    let name = file
+        .xmp
+        .as_ref()
+        .and_then(|xmp| xmp.creator.as_deref())
+        .or_else(|| file.exif.attribution());
+    Ok(match name {
+        Some(name) => Some(Attribution::get_or_create(db, name)?.id),
+        None => None,
+    })
+}
+
+/// Add tags and people from xmp data to a photo, and set its grade.
+///
+/// If the photo has a different grade, the xmp rating only replaces
+/// it if `xmp_wins`.
+fn apply_xmp(
+    db: &PgConnection,
+    photo: Photo,
+    xmp: &XmpData,
+    xmp_wins: bool,
+) -> Result<Modification<Photo>, Error> {
+    use crate::schema::photo_people::dsl as ph;
+    use crate::schema::photo_tags::dsl as pt;
+    use crate::schema::photos::dsl as p;
+    let mut changed = false;
+    for name in &xmp.keywords {
+        let tag = Tag::get_or_create_name(db, name)?;
+        let q = pt::photo_tags
+            .filter(pt::photo_id.eq(photo.id))
+            .filter(pt::tag_id.eq(tag.id));
+        if q.first::<PhotoTag>(db).optional()?.is_none() {
+            info!("Add {:?} on photo #{} from xmp", tag.tag_name, photo.id);
+            insert_into(pt::photo_tags)
+                .values((pt::photo_id.eq(photo.id), pt::tag_id.eq(tag.id)))
+                .execute(db)?;
+            changed = true;
+        }
+    }
+    for name in &xmp.people {
+        let person = Person::get_or_create_name(db, name)?;
+        let q = ph::photo_people
+            .filter(ph::photo_id.eq(photo.id))
+            .filter(ph::person_id.eq(person.id));
+        if q.first::<PhotoPerson>(db).optional()?.is_none() {
+            info!("Add {:?} on photo #{} from xmp", person, photo.id);
+            insert_into(ph::photo_people)
+                .values((
+                    ph::photo_id.eq(photo.id),
+                    ph::person_id.eq(person.id),
+                ))
+                .execute(db)?;
+            changed = true;
+        }
+    }
+    match (xmp.grade(), photo.grade) {
+        (Some(grade), Some(old)) if grade != old && !xmp_wins => {
+            info!(
+                "Photo #{}: keeping grade {}, xmp rating is {:?}",
+                photo.id, old, xmp.rating,
+            );
+        }
+        (Some(grade), old) if old != Some(grade) => {
+            let photo = diesel::update(p::photos.find(photo.id))
+                .set(p::grade.eq(grade))
+                .get_result::<Photo>(db)?;
+            return Ok(Modification::Updated(photo));
+        }
+        _ => (),
+    }
+    Ok(if changed {
+        Modification::Updated(photo)
+    } else {
+        Modification::Unchanged(photo)
+    })
+}
+
+/// Find a known photo with the given content hash whose file is gone.
+///
+/// If there is one, and no photo is known by `file_path`, move it to
+/// `file_path` and return the old path.
+fn relink_moved(
+    db: &PgConnection,
+    photos: &PhotosDir,
+    file_path: &str,
+    hash: &str,
+) -> Result<Option<String>, Error> {
+    use crate::schema::photos::dsl as p;
+    if p::photos
+        .filter(p::path.eq(file_path))
+        .select(p::id)
+        .first::<i32>(db)
+        .optional()?
+        .is_some()
+    {
+        return Ok(None);
+    }
+    let candidates = p::photos
+        .filter(p::content_hash.eq(hash))
+        .order(p::id)
+        .load::<Photo>(db)?;
+    for photo in candidates {
+        if !photos.has_file(&photo.path) {
+            info!("Moving #{} from {} to {}", photo.id, photo.path, file_path);
+            diesel::update(p::photos.find(photo.id))
+                .set(p::path.eq(file_path))
+                .execute(db)?;
+            return Ok(Some(photo.path));
+        }
+    }
+    Ok(None)
+}
+
 fn find_camera(
     db: &PgConnection,
     exif: &ExifData,
@@ -127,3 +765,64 @@ fn find_camera(
     }
     Ok(None)
 }
+
+fn find_lens(
+    db: &PgConnection,
+    exif: &ExifData,
+) -> Result<Option<Lens>, Error> {
+    if let Some((make, model)) = exif.lens() {
+        return Ok(Some(Lens::get_or_create(db, make, model)?));
+    }
+    Ok(None)
+}
+
+#[test]
+#[ignore = "needs a database with the rphotos schema in DATABASE_URL"]
+fn unpaired_raw_is_saved_and_linked_later() {
+    use crate::schema::photos::dsl as p;
+    let db = PgConnection::establish(&std::env::var("DATABASE_URL").unwrap())
+        .unwrap();
+    let pd = PhotosDir::new(Path::new("/nonexistent"));
+    let opt = SaveOpt {
+        relink: false,
+        xmp: false,
+        xmp_wins: false,
+    };
+    let file = |path: &str| {
+        let mut exif = ExifData::default();
+        exif.width = Some(60);
+        exif.height = Some(40);
+        LoadedFile {
+            path: path.into(),
+            fingerprint: Fingerprint {
+                size: 17,
+                mtime: chrono::NaiveDate::from_ymd_opt(2021, 5, 1)
+                    .and_then(|d| d.and_hms_opt(12, 0, 0))
+                    .unwrap(),
+                inode: None,
+            },
+            hash: format!("test-hash-of-{}", path),
+            dhash: None,
+            dhash_failed: false,
+            format: None,
+            duration: None,
+            exif,
+            xmp: None,
+            sidecar: None,
+        }
+    };
+    db.test_transaction::<_, Error, _>(|| {
+        let raw_path = "rphotos-test/unpaired/IMG_0001.CR2";
+        let saved = save_raw(&db, &pd, &file(raw_path), opt)?;
+        assert!(matches!(saved, Saved::Created));
+        let raw =
+            p::photos.filter(p::path.eq(raw_path)).first::<Photo>(&db)?;
+        assert_eq!(raw.raw_of, None);
+
+        let jpeg = file("rphotos-test/unpaired/IMG_0001.jpg");
+        let (jpeg, _) = save_photo(&db, &pd, &jpeg, opt)?;
+        let raw = p::photos.find(raw.id).first::<Photo>(&db)?;
+        assert_eq!(raw.raw_of, Some(jpeg.id));
+        Ok(())
+    });
+}
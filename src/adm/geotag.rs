@@ -0,0 +1,253 @@
+use super::result::Error;
+use super::shifttime::{format_shift, parse_shift};
+use crate::fetch_places::OverpassOpt;
+use crate::models::Photo;
+use crate::schema::photos::dsl as p;
+use crate::schema::positions::dsl as pos;
+use crate::DbOpt;
+use chrono::{DateTime, Duration, NaiveDateTime};
+use diesel::insert_into;
+use diesel::prelude::*;
+use log::{debug, info, warn};
+use roxmltree::Document;
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct Geotag {
+    #[structopt(flatten)]
+    db: DbOpt,
+    #[structopt(flatten)]
+    overpass: OverpassOpt,
+
+    /// How much the camera clock is ahead of the track, like +0:05.
+    ///
+    /// Photos without a known utc offset are taken to be in the
+    /// timezone of this host, this can also correct that.
+    #[structopt(
+        long,
+        parse(try_from_str = parse_shift),
+        allow_hyphen_values = true,
+        default_value = "+0:00"
+    )]
+    clock_offset: i32,
+
+    /// Max seconds between the track points around a photo.
+    ///
+    /// Photos taken where the track has a longer gap are not tagged.
+    #[structopt(long, default_value = "300")]
+    max_gap: i64,
+
+    /// Fetch places for each photo that gets a position.
+    #[structopt(long)]
+    fetch_places: bool,
+
+    /// Show what positions would be set, without changing anything.
+    #[structopt(long, short = "n")]
+    dry_run: bool,
+
+    /// The gpx files to read tracks from.
+    #[structopt(required = true)]
+    tracks: Vec<PathBuf>,
+}
+
+impl Geotag {
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut track = Track::default();
+        for path in &self.tracks {
+            track.read(path)?;
+        }
+        track.points.sort_by_key(|point| point.time);
+        let (first, last) = match (track.points.first(), track.points.last()) {
+            (Some(first), Some(last)) => (first.time, last.time),
+            _ => return Err(Error::Other("No track points found".into())),
+        };
+        info!("Read {} track points", track.points.len());
+        let pool = self.db.create_pool()?;
+        let db = pool.get()?;
+        let offset = Duration::seconds(self.clock_offset.into());
+        // Utc offsets are at most 14 hours, so this gets all candidates.
+        let zone = Duration::hours(14);
+        // Like shown photos, so not raw files or photos that are gone.
+        let photos = Photo::query(true)
+            .filter(p::date.is_not_null())
+            .filter(p::date.ge(first + offset - zone))
+            .filter(p::date.le(last + offset + zone))
+            .filter(p::id.ne_all(pos::positions.select(pos::photo_id)))
+            .order((p::date, p::id))
+            .load::<Photo>(&db)?;
+        let max_gap = Duration::seconds(self.max_gap);
+        let mut tagged = Vec::new();
+        for photo in photos {
+            let time = match photo.utc_date() {
+                Some(time) => time - offset,
+                None => continue,
+            };
+            let (lat, long) = match track.position_at(time, max_gap) {
+                Some(position) => position,
+                None => {
+                    debug!("No position for #{} at {}", photo.id, time);
+                    continue;
+                }
+            };
+            println!(
+                "#{}: {} at {} UTC: {:.6} {:.6}",
+                photo.id, photo.path, time, lat, long,
+            );
+            if !self.dry_run {
+                insert_into(pos::positions)
+                    .values((
+                        pos::photo_id.eq(photo.id),
+                        pos::latitude.eq((lat * 1e6) as i32),
+                        pos::longitude.eq((long * 1e6) as i32),
+                    ))
+                    .execute(&db)?;
+            }
+            tagged.push(photo.id);
+        }
+        if self.dry_run {
+            println!(
+                "Would tag {} photos, camera clock offset {}.",
+                tagged.len(),
+                format_shift(self.clock_offset),
+            );
+            return Ok(());
+        }
+        println!("Tagged {} photos.", tagged.len());
+        if self.fetch_places {
+            for id in tagged {
+                if let Err(e) =
+                    self.overpass.update_image_places(&pool, id).await
+                {
+                    warn!("Failed to fetch places for #{}: {:?}", id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Track {
+    points: Vec<TrackPoint>,
+}
+
+#[derive(Debug, PartialEq)]
+struct TrackPoint {
+    /// The time in UTC.
+    time: NaiveDateTime,
+    lat: f64,
+    long: f64,
+}
+
+impl Track {
+    /// Read the track points of a gpx file.
+    fn read(&mut self, path: &Path) -> Result<(), Error> {
+        let gpx =
+            fs::read_to_string(path).map_err(|e| Error::in_file(&e, path))?;
+        let n = self.parse(&gpx).map_err(|e| Error::in_file(&e, path))?;
+        info!("Read {} points from {}", n, path.display());
+        Ok(())
+    }
+
+    /// Add the timed track points of a gpx document.
+    fn parse(&mut self, gpx: &str) -> Result<usize, roxmltree::Error> {
+        let doc = Document::parse(gpx)?;
+        let before = self.points.len();
+        let points = doc
+            .descendants()
+            .filter(|n| n.tag_name().name() == "trkpt")
+            .filter_map(|point| {
+                let time = point
+                    .children()
+                    .find(|n| n.tag_name().name() == "time")
+                    .and_then(|n| n.text())
+                    .and_then(|t| {
+                        DateTime::parse_from_rfc3339(t.trim()).ok()
+                    })?;
+                Some(TrackPoint {
+                    time: time.naive_utc(),
+                    lat: point.attribute("lat")?.parse().ok()?,
+                    long: point.attribute("lon")?.parse().ok()?,
+                })
+            });
+        self.points.extend(points);
+        Ok(self.points.len() - before)
+    }
+
+    /// Interpolate the position at `time` between the nearest points.
+    ///
+    /// The points must be sorted by time.  There is no position if
+    /// the points around `time` are more than `max_gap` apart.
+    fn position_at(
+        &self,
+        time: NaiveDateTime,
+        max_gap: Duration,
+    ) -> Option<(f64, f64)> {
+        let i = self.points.partition_point(|point| point.time < time);
+        let after = self.points.get(i)?;
+        if after.time == time {
+            return Some((after.lat, after.long));
+        }
+        let before = self.points.get(i.checked_sub(1)?)?;
+        let gap = after.time - before.time;
+        if gap > max_gap {
+            return None;
+        }
+        let part = (time - before.time).num_milliseconds() as f64
+            / gap.num_milliseconds() as f64;
+        Some((
+            before.lat + part * (after.lat - before.lat),
+            before.long + part * (after.long - before.long),
+        ))
+    }
+}
+
+#[cfg(test)]
+fn test_track() -> Track {
+    let mut track = Track::default();
+    track
+        .parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg>
+    <trkpt lat="59.3000" lon="18.0000"><time>2021-05-16T12:00:00Z</time></trkpt>
+    <trkpt lat="59.3100" lon="18.0200"><time>2021-05-16T12:02:00Z</time></trkpt>
+    <trkpt lat="59.3200" lon="18.0200"><time>2021-05-16T14:30:00+02:00</time></trkpt>
+    <trkpt lat="60.0000" lon="19.0000"></trkpt>
+  </trkseg></trk>
+</gpx>"#,
+        )
+        .unwrap();
+    track
+}
+
+#[cfg(test)]
+fn at(h: u32, m: u32, s: u32) -> NaiveDateTime {
+    chrono::NaiveDate::from_ymd_opt(2021, 5, 16)
+        .and_then(|d| d.and_hms_opt(h, m, s))
+        .unwrap()
+}
+
+#[test]
+fn parse_gpx() {
+    let track = test_track();
+    assert_eq!(track.points.len(), 3);
+    assert_eq!(track.points[2].time, at(12, 30, 0));
+}
+
+#[test]
+fn interpolate_position() {
+    let mut track = test_track();
+    track.points.sort_by_key(|point| point.time);
+    let gap = Duration::minutes(5);
+    let (lat, long) = track.position_at(at(12, 1, 0), gap).unwrap();
+    assert!((lat - 59.305).abs() < 1e-9);
+    assert!((long - 18.01).abs() < 1e-9);
+    assert_eq!(track.position_at(at(12, 0, 0), gap), Some((59.3, 18.0)));
+    assert_eq!(track.position_at(at(11, 59, 59), gap), None);
+    assert_eq!(track.position_at(at(12, 10, 0), gap), None);
+    assert_eq!(track.position_at(at(12, 1, 0), Duration::seconds(60)), None);
+}
@@ -1,7 +1,15 @@
+pub mod attribution;
+pub mod copyposition;
+pub mod duplicates;
+pub mod exportxmp;
 pub mod findphotos;
+pub mod geotag;
 pub mod makepublic;
+pub mod orphans;
 pub mod precache;
 pub mod result;
+pub mod shifttime;
 pub mod stats;
 pub mod storestatics;
 pub mod users;
+pub mod watch;
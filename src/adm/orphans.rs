@@ -0,0 +1,155 @@
+use super::result::Error;
+use crate::models::Photo;
+use crate::photosdir::PhotosDir;
+use crate::{DbOpt, DirOpt};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::{delete, update};
+use log::info;
+use std::io::{self, BufRead, Write};
+use structopt::clap::ArgGroup;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+#[structopt(group = ArgGroup::with_name("action"))]
+pub struct Orphans {
+    #[structopt(flatten)]
+    db: DbOpt,
+    #[structopt(flatten)]
+    photos: DirOpt,
+
+    /// Mark the orphaned photos as missing.
+    ///
+    /// Missing photos are not shown in any listings.  A photo that is
+    /// found again by findphotos is no longer marked missing.
+    #[structopt(long, group = "action")]
+    mark_missing: bool,
+    /// Delete the orphaned photos.
+    ///
+    /// The tags, people, places and positions of the photos are also
+    /// deleted.
+    #[structopt(long, group = "action")]
+    delete: bool,
+    /// Delete without asking for confirmation.
+    #[structopt(long, short)]
+    yes: bool,
+
+    /// Base directory to search in (relative to the image root).
+    base: Vec<String>,
+}
+
+impl Orphans {
+    pub fn run(&self) -> Result<(), Error> {
+        let pd = PhotosDir::new(&self.photos.photos_dir);
+        let db = self.db.connect()?;
+        let orphans = find_orphans(&db, &pd, &self.base)?;
+        for photo in &orphans {
+            println!("#{}: {}", photo.id, photo.path);
+        }
+        println!("Found {} photos with missing files.", orphans.len());
+        if orphans.is_empty() {
+            return Ok(());
+        }
+        let ids = orphans.iter().map(|p| p.id).collect::<Vec<_>>();
+        if self.mark_missing {
+            use crate::schema::photos::dsl as p;
+            let n = update(p::photos.filter(p::id.eq_any(&ids)))
+                .set(p::is_missing.eq(true))
+                .execute(&db)?;
+            println!("Marked {} photos as missing.", n);
+        } else if self.delete {
+            if self.yes || confirm(&format!("Delete {} photos?", ids.len()))? {
+                let n = delete_photos(&db, &ids)?;
+                println!("Deleted {} photos.", n);
+            } else {
+                println!("Nothing deleted.");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find photos in the database whose file is gone.
+///
+/// If `base` is not empty, only photos in those directories are
+/// checked.
+pub fn find_orphans(
+    db: &PgConnection,
+    photos: &PhotosDir,
+    base: &[String],
+) -> Result<Vec<Photo>, Error> {
+    use crate::schema::photos::dsl as p;
+    Ok(p::photos
+        .order(p::path)
+        .load::<Photo>(db)?
+        .into_iter()
+        .filter(|photo| {
+            base.is_empty() || base.iter().any(|b| in_dir(&photo.path, b))
+        })
+        .filter(|photo| !photos.has_file(&photo.path))
+        .collect())
+}
+
+/// True if `path` is `dir` or a file somewhere below it.
+fn in_dir(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    path == dir
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Delete photos, with everything referring to them.
+///
+/// The raw files of the photos are also deleted from the database.
+fn delete_photos(db: &PgConnection, ids: &[i32]) -> Result<usize, Error> {
+    use crate::schema::photo_people::dsl as ph;
+    use crate::schema::photo_places::dsl as pl;
+    use crate::schema::photo_tags::dsl as pt;
+    use crate::schema::photos::dsl as p;
+    use crate::schema::positions::dsl as pos;
+    db.transaction(|| {
+        let raws = p::photos
+            .filter(p::raw_of.eq_any(ids))
+            .filter(p::id.ne_all(ids))
+            .select(p::id)
+            .load::<i32>(db)?;
+        if !raws.is_empty() {
+            info!("Deleting {} raw files", raws.len());
+        }
+        let ids = &[ids, &raws].concat();
+        let n = delete(pt::photo_tags.filter(pt::photo_id.eq_any(ids)))
+            .execute(db)?;
+        info!("Deleted {} photo tags", n);
+        let n = delete(ph::photo_people.filter(ph::photo_id.eq_any(ids)))
+            .execute(db)?;
+        info!("Deleted {} photo people", n);
+        let n = delete(pl::photo_places.filter(pl::photo_id.eq_any(ids)))
+            .execute(db)?;
+        info!("Deleted {} photo places", n);
+        let n = delete(pos::positions.filter(pos::photo_id.eq_any(ids)))
+            .execute(db)?;
+        info!("Deleted {} positions", n);
+        Ok(delete(p::photos.filter(p::id.eq_any(ids))).execute(db)?)
+    })
+}
+
+/// Ask a yes or no question on the terminal.
+fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[test]
+fn in_dir_only_matches_whole_names() {
+    assert!(in_dir("2020/07/a.jpg", "2020"));
+    assert!(in_dir("2020/07/a.jpg", "2020/"));
+    assert!(in_dir("2020/07/a.jpg", "2020/07"));
+    assert!(in_dir("2020", "2020"));
+    assert!(!in_dir("20201/a.jpg", "2020"));
+    assert!(!in_dir("2020-old/a.jpg", "2020"));
+}
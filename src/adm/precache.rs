@@ -1,11 +1,15 @@
 use super::result::Error;
-use crate::models::{Photo, SizeTag};
-use crate::photosdir::{get_scaled_jpeg, PhotosDir};
+use crate::cache::Cache;
+use crate::metadata::MetadataOpt;
+use crate::models::{Photo, ScaledFormat, SizeTag};
+use crate::photosdir::{get_scaled_image, PhotosDir};
 use crate::schema::photos::dsl::{date, is_public};
+use crate::watermark::{Mark, Watermark, WatermarkOpt};
 use crate::{CacheOpt, DbOpt, DirOpt};
+use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use log::{debug, info};
-use r2d2_memcache::memcache::Client;
+use std::iter::once;
 use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
@@ -18,10 +22,18 @@ pub struct Args {
     db: DbOpt,
     #[structopt(flatten)]
     photos: DirOpt,
+    #[structopt(flatten)]
+    metadata: MetadataOpt,
+    #[structopt(flatten)]
+    watermark: WatermarkOpt,
 
     /// Max time (in seconds) to work.
     #[structopt(long, short = "t", default_value = "10")]
     max_time: u64,
+
+    /// The formats to store scaled images in: jpeg, webp and/or avif.
+    #[structopt(long, default_value = "jpeg,webp,avif", use_delimiter = true)]
+    formats: Vec<ScaledFormat>,
 }
 
 impl Args {
@@ -31,33 +43,41 @@ impl Args {
     /// overwhelm the host while precaching.
     /// The images are handled in public first, new first order, to have
     /// the probably most requested images precached as soon as possible.
+    /// Public images are also stored with the watermark, if any, that
+    /// anonymous users get.
     pub async fn run(&self) -> Result<(), Error> {
         let max_time = Duration::from_secs(self.max_time);
         let timer = Instant::now();
-        let cache = Client::connect(self.cache.memcached_url.as_ref())?;
+        let cache = self.cache.open_shared()?;
         let size = SizeTag::Small;
         let (mut n, mut n_stored) = (0, 0);
+        let db = self.db.connect()?;
         let photos = Photo::query(true)
             .order((is_public.desc(), date.desc().nulls_last()))
-            .load::<Photo>(&self.db.connect()?)?;
-        let no_expire = 0;
+            .load::<Photo>(&db)?;
         let pd = PhotosDir::new(&self.photos.photos_dir);
-        for photo in photos {
+        let watermark = self.watermark.load()?;
+        let scaled = photos.iter().filter(|p| !p.is_video()).flat_map(|p| {
+            let mark = public_mark(watermark.as_ref(), &db, p, size);
+            once(None).chain(mark.map(Some)).flat_map(move |mark| {
+                self.formats.iter().map(move |f| (p, *f, mark.clone()))
+            })
+        });
+        for (photo, format, mark) in scaled {
             n += 1;
-            let key = &photo.cache_key(size);
-            if cache.get::<Vec<u8>>(key)?.is_none() {
-                let path = pd.get_raw_path(&photo);
-                let size = size.px();
-                let data = get_scaled_jpeg(path, photo.rotation, size)
-                    .await
-                    .map_err(|e| {
-                    Error::Other(format!(
-                        "Failed to scale #{} ({}): {:?}",
-                        photo.id, photo.path, e,
-                    ))
-                })?;
-                cache.set(key, &data[..], no_expire)?;
-                debug!("Cache: stored {} for {}", key, photo.path);
+            let key = &cache_key(photo, size, format, mark.as_ref());
+            if cache.get(key)?.is_none() {
+                let xmp = self.metadata.public_xmp(&db, photo)?;
+                store_scaled(
+                    cache.as_ref(),
+                    &pd,
+                    photo,
+                    size,
+                    format,
+                    xmp,
+                    mark,
+                )
+                .await?;
                 n_stored += 1;
                 if timer.elapsed() > max_time {
                     break;
@@ -81,3 +101,64 @@ impl Args {
         Ok(())
     }
 }
+
+/// The watermark anonymous users get on `photo` scaled to `size`.
+///
+/// Only public photos are shown to anonymous users, so others get
+/// no mark.
+pub fn public_mark(
+    watermark: Option<&Watermark>,
+    db: &PgConnection,
+    photo: &Photo,
+    size: SizeTag,
+) -> Option<Mark> {
+    if !photo.is_public {
+        return None;
+    }
+    watermark?.mark(size.px(), || photo.load_attribution(db))
+}
+
+/// The cache key for `photo` in `size` and `format`, with `mark`.
+fn cache_key(
+    photo: &Photo,
+    size: SizeTag,
+    format: ScaledFormat,
+    mark: Option<&Mark>,
+) -> String {
+    let key = photo.cache_key(size, format);
+    match mark {
+        Some(mark) => Photo::watermarked_key(&key, mark),
+        None => key,
+    }
+}
+
+/// Scale a photo to `size` and store it in the cache.
+///
+/// Any previously cached image of that size, format and mark is
+/// replaced.  The `xmp` packet, if any, is embedded in the image and
+/// the `mark`, if any, is drawn on it.
+pub async fn store_scaled(
+    cache: &dyn Cache,
+    pd: &PhotosDir,
+    photo: &Photo,
+    size: SizeTag,
+    format: ScaledFormat,
+    xmp: Option<String>,
+    mark: Option<Mark>,
+) -> Result<(), Error> {
+    let key = &cache_key(photo, size, format, mark.as_ref());
+    let path = pd.get_raw_path(photo);
+    let (rotation, flip) = (photo.rotation, photo.flip);
+    let data =
+        get_scaled_image(path, rotation, flip, size.px(), format, xmp, mark)
+            .await
+            .map_err(|e| {
+                Error::Other(format!(
+                    "Failed to scale #{} ({}): {:?}",
+                    photo.id, photo.path, e,
+                ))
+            })?;
+    cache.set(key, &data, None)?;
+    debug!("Cache: stored {} for {}", key, photo.path);
+    Ok(())
+}
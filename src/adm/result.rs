@@ -2,7 +2,6 @@ use crate::fetch_places;
 use chrono::ParseError as ChronoParseError;
 use diesel::prelude::ConnectionError;
 use diesel::result::Error as DieselError;
-use r2d2_memcache::memcache::MemcacheError;
 use r2d2_memcache::r2d2::Error as R2d2Error;
 use std::convert::From;
 use std::num::ParseIntError;
@@ -18,7 +17,7 @@ pub enum Error {
     UnknownOrientation(u32),
     BadTimeFormat(ChronoParseError),
     BadIntFormat(ParseIntError),
-    Cache(MemcacheError),
+    Cache(crate::cache::Error),
     MissingWidth,
     MissingHeight,
     PlacesFailed(fetch_places::Error),
@@ -42,7 +41,7 @@ impl fmt::Display for Error {
             }
             Error::BadTimeFormat(ref e) => write!(f, "Bad time value: {}", e),
             Error::BadIntFormat(ref e) => write!(f, "Bad int value: {}", e),
-            Error::Cache(ref e) => write!(f, "Memcached error: {}", e),
+            Error::Cache(ref e) => e.fmt(f),
             Error::MissingHeight => write!(f, "Missing height property"),
             Error::MissingWidth => write!(f, "Missing width property"),
             Error::PlacesFailed(ref e) => {
@@ -53,8 +52,8 @@ impl fmt::Display for Error {
     }
 }
 
-impl From<MemcacheError> for Error {
-    fn from(e: MemcacheError) -> Self {
+impl From<crate::cache::Error> for Error {
+    fn from(e: crate::cache::Error) -> Self {
         Error::Cache(e)
     }
 }
@@ -0,0 +1,241 @@
+use super::result::Error;
+use crate::models::Photo;
+use crate::schema::photos::dsl as p;
+use crate::DbOpt;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use diesel::dsl::IntervalDsl;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::update;
+use log::info;
+use structopt::clap::ArgGroup;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+#[structopt(group = ArgGroup::with_name("selection").required(true).multiple(true))]
+pub struct ShiftTime {
+    #[structopt(flatten)]
+    db: DbOpt,
+
+    /// How much to shift the photos, like +1:00 or -0:30:15.
+    #[structopt(long, parse(try_from_str = parse_shift), allow_hyphen_values = true)]
+    by: i32,
+
+    /// Only shift photos taken with this camera model.
+    #[structopt(long, group = "selection")]
+    camera: Option<String>,
+
+    /// Only shift photos with a path starting with this.
+    #[structopt(long, group = "selection")]
+    path: Option<String>,
+
+    /// Only shift photos taken at or after this time.
+    ///
+    /// The time is given as it is before the shift, like 2021-05-01
+    /// or 2021-05-01T14:30.
+    #[structopt(long, group = "selection", parse(try_from_str = parse_since))]
+    since: Option<NaiveDateTime>,
+
+    /// Only shift photos taken at or before this time.
+    #[structopt(long, group = "selection", parse(try_from_str = parse_until))]
+    until: Option<NaiveDateTime>,
+
+    /// Show what would be shifted, without changing anything.
+    #[structopt(long, short = "n")]
+    dry_run: bool,
+}
+
+impl ShiftTime {
+    pub fn run(&self) -> Result<(), Error> {
+        let db = self.db.connect()?;
+        let camera_id = match &self.camera {
+            Some(model) => Some(find_camera(&db, model)?),
+            None => None,
+        };
+        let selection = Selection {
+            camera_id,
+            path: self.path.clone(),
+            since: self.since,
+            until: self.until,
+        };
+        let photos = selection.load(&db)?;
+        for photo in &photos {
+            if let Some(date) = photo.date {
+                println!(
+                    "#{}: {} {} -> {}",
+                    photo.id,
+                    photo.path,
+                    date,
+                    date + Duration::seconds(self.by.into()),
+                );
+            }
+        }
+        if self.dry_run {
+            println!(
+                "Would shift {} photos by {}.",
+                photos.len(),
+                format_shift(self.by),
+            );
+        } else {
+            let ids = photos.iter().map(|p| p.id).collect::<Vec<_>>();
+            let n = shift_photos(&db, &ids, self.by)?;
+            println!("Shifted {} photos by {}.", n, format_shift(self.by));
+        }
+        Ok(())
+    }
+}
+
+/// A selection of photos to shift.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub camera_id: Option<i32>,
+    pub path: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+impl Selection {
+    pub fn is_empty(&self) -> bool {
+        self.camera_id.is_none()
+            && self.path.is_none()
+            && self.since.is_none()
+            && self.until.is_none()
+    }
+
+    /// Load the selected photos that have a date, in date order.
+    pub fn load(&self, db: &PgConnection) -> Result<Vec<Photo>, Error> {
+        if self.is_empty() {
+            return Err(Error::Other("Refusing to shift all photos".into()));
+        }
+        let mut photos = p::photos.filter(p::date.is_not_null()).into_boxed();
+        if let Some(camera_id) = self.camera_id {
+            photos = photos.filter(p::camera_id.eq(camera_id));
+        }
+        if let Some(path) = &self.path {
+            let pattern = format!(
+                "{}%",
+                path.replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_"),
+            );
+            photos = photos.filter(p::path.like(pattern));
+        }
+        if let Some(since) = self.since {
+            photos = photos.filter(p::date.ge(since));
+        }
+        if let Some(until) = self.until {
+            photos = photos.filter(p::date.le(until));
+        }
+        Ok(photos.order((p::date, p::id)).load(db)?)
+    }
+}
+
+/// Shift the date of photos by `seconds`.
+///
+/// The shift is also added to the `time_shift` of each photo, so
+/// findphotos applies it to the time read from the file.
+pub fn shift_photos(
+    db: &PgConnection,
+    ids: &[i32],
+    seconds: i32,
+) -> Result<usize, Error> {
+    let n = update(p::photos.filter(p::id.eq_any(ids)))
+        .set((
+            p::date.eq(p::date + seconds.seconds()),
+            p::time_shift.eq(p::time_shift + seconds),
+        ))
+        .execute(db)?;
+    info!("Shifted {} photos by {} seconds", n, seconds);
+    Ok(n)
+}
+
+fn find_camera(db: &PgConnection, model: &str) -> Result<i32, Error> {
+    use crate::schema::cameras::dsl as c;
+    c::cameras
+        .filter(c::model.eq(model))
+        .select(c::id)
+        .first(db)
+        .optional()?
+        .ok_or_else(|| Error::Other(format!("No camera {:?} found", model)))
+}
+
+/// Parse a shift like +1:00, -0:30 or +0:00:15 into seconds.
+pub fn parse_shift(s: &str) -> Result<i32, String> {
+    let bad = || format!("Bad shift {:?}, expected like +1:00", s);
+    let s = s.trim();
+    let (sign, hms) = if let Some(hms) = s.strip_prefix('+') {
+        (1, hms)
+    } else if let Some(hms) = s.strip_prefix('-') {
+        (-1, hms)
+    } else {
+        return Err(bad());
+    };
+    let mut seconds = 0;
+    let parts = hms.split(':').collect::<Vec<_>>();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(bad());
+    }
+    for (i, (part, unit)) in parts.iter().zip(&[3600, 60, 1]).enumerate() {
+        let value = part.parse::<u16>().map_err(|_| bad())?;
+        if i > 0 && value > 59 {
+            return Err(bad());
+        }
+        seconds += i32::from(value) * unit;
+    }
+    Ok(sign * seconds)
+}
+
+/// Format a shift in seconds like [`parse_shift`] parses it.
+pub fn format_shift(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let s = seconds.abs();
+    if s % 60 == 0 {
+        format!("{}{}:{:02}", sign, s / 3600, s / 60 % 60)
+    } else {
+        format!("{}{}:{:02}:{:02}", sign, s / 3600, s / 60 % 60, s % 60)
+    }
+}
+
+/// Parse the start of a time range, a date means its start.
+pub fn parse_since(s: &str) -> Result<NaiveDateTime, String> {
+    parse_time(s, NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+}
+
+/// Parse the end of a time range, a date means its end.
+pub fn parse_until(s: &str) -> Result<NaiveDateTime, String> {
+    parse_time(s, NaiveTime::from_hms_opt(23, 59, 59).unwrap())
+}
+
+fn parse_time(s: &str, time: NaiveTime) -> Result<NaiveDateTime, String> {
+    let s = s.trim().replacen(' ', "T", 1);
+    NaiveDateTime::parse_from_str(&s, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(&s, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(&s, "%Y-%m-%d").map(|d| d.and_time(time))
+        })
+        .map_err(|_| format!("Bad time {:?}, expected like 2021-05-01", s))
+}
+
+#[test]
+fn shifts() {
+    assert_eq!(parse_shift("+1:00"), Ok(3600));
+    assert_eq!(parse_shift("-0:30"), Ok(-1800));
+    assert_eq!(parse_shift("+0:00:15"), Ok(15));
+    assert!(parse_shift("1:00").is_err());
+    assert!(parse_shift("+1").is_err());
+    assert!(parse_shift("+1:75").is_err());
+    assert!(parse_shift("+0:30:60").is_err());
+    assert_eq!(format_shift(-1800), "-0:30");
+    assert_eq!(format_shift(3615), "+1:00:15");
+}
+
+#[test]
+fn time_bounds() {
+    let day = NaiveDate::from_ymd_opt(2021, 5, 1).unwrap();
+    let at = |h, m, s| day.and_hms_opt(h, m, s).unwrap();
+    assert_eq!(parse_since("2021-05-01"), Ok(at(0, 0, 0)));
+    assert_eq!(parse_until("2021-05-01"), Ok(at(23, 59, 59)));
+    assert_eq!(parse_since("2021-05-01T14:30"), Ok(at(14, 30, 0)));
+    assert_eq!(parse_since("2021-05-01 14:30:10"), Ok(at(14, 30, 10)));
+}
@@ -0,0 +1,254 @@
+use super::findphotos::{save_photo, save_raw, LoadedFile, SaveOpt, Saved};
+use super::precache::{public_mark, store_scaled};
+use super::result::Error;
+use crate::cache::Cache;
+use crate::dbopt::PgPool;
+use crate::fetch_places::OverpassOpt;
+use crate::metadata::MetadataOpt;
+use crate::models::{ScaledFormat, SizeTag};
+use crate::photosdir::{is_raw, PhotosDir};
+use crate::watermark::{Watermark, WatermarkOpt};
+use crate::{CacheOpt, DbOpt, DirOpt};
+use diesel::prelude::*;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use structopt::StructOpt;
+use tokio::time::sleep;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct Watch {
+    #[structopt(flatten)]
+    db: DbOpt,
+    #[structopt(flatten)]
+    photos: DirOpt,
+    #[structopt(flatten)]
+    cache: CacheOpt,
+    #[structopt(flatten)]
+    overpass: OverpassOpt,
+    #[structopt(flatten)]
+    metadata: MetadataOpt,
+    #[structopt(flatten)]
+    watermark: WatermarkOpt,
+
+    /// Seconds a file must be left alone before it is read.
+    ///
+    /// This avoids reading files that are still being written.
+    #[structopt(long, default_value = "5")]
+    settle: u64,
+
+    #[structopt(flatten)]
+    save: SaveOpt,
+
+    /// Store the small thumbnails of each new or changed photo in cache.
+    #[structopt(long)]
+    precache: bool,
+
+    /// Fetch places for each new or changed photo with a position.
+    #[structopt(long)]
+    fetch_places: bool,
+}
+
+impl Watch {
+    /// Watch the photo directory, saving photos as files are written.
+    ///
+    /// Files that exist when the watch starts are not read, run
+    /// findphotos for that.
+    pub async fn run(&self) -> Result<(), Error> {
+        let pd = PhotosDir::new(&self.photos.photos_dir);
+        let pool = self.db.create_pool()?;
+        let cache = if self.precache {
+            Some(self.cache.open_shared()?)
+        } else {
+            None
+        };
+        let watermark = self.watermark.load()?;
+        let settle = Duration::from_secs(self.settle);
+        let mut watcher = Watcher::new()?;
+        watcher.add_tree(&self.photos.photos_dir, None)?;
+        info!("Watching {} directories", watcher.dirs.len());
+        loop {
+            watcher.read_events()?;
+            let mut ready = watcher.settled(settle);
+            // Raw files last, so their jpeg files are known.
+            ready.sort_by_key(is_raw);
+            for path in ready {
+                if let Err(e) =
+                    self.ingest(&pd, &pool, &cache, &watermark, &path).await
+                {
+                    warn!("Failed to save {}: {}", path.display(), e);
+                }
+            }
+            sleep(Duration::from_millis(500)).await;
+        }
+    }
+
+    /// Read and save a single file, if it is an image.
+    async fn ingest(
+        &self,
+        pd: &PhotosDir,
+        pool: &PgPool,
+        cache: &Option<Box<dyn Cache>>,
+        watermark: &Option<Watermark>,
+        path: &Path,
+    ) -> Result<(), Error> {
+        let file = match pd.get_file(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("{} is gone before it was read", path.display());
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let file = match LoadedFile::read(&file, self.save.xmp)? {
+            Some(file) => file,
+            None => {
+                debug!("{:?} is no pic.", file.path);
+                return Ok(());
+            }
+        };
+        let db = pool.get()?;
+        if is_raw(&file.path) {
+            db.transaction(|| save_raw(&db, pd, &file, self.save))?;
+            return Ok(());
+        }
+        let (photo, saved) =
+            db.transaction(|| save_photo(&db, pd, &file, self.save))?;
+        if let Saved::Unchanged = saved {
+            return Ok(());
+        }
+        if let Some(cache) = cache.as_ref().filter(|_| !photo.is_video()) {
+            let xmp = self.metadata.public_xmp(&db, &photo)?;
+            let size = SizeTag::Small;
+            let mark = public_mark(watermark.as_ref(), &db, &photo, size);
+            for mark in once(None).chain(mark.map(Some)) {
+                for format in &ScaledFormat::ALL {
+                    store_scaled(
+                        cache.as_ref(),
+                        pd,
+                        &photo,
+                        size,
+                        *format,
+                        xmp.clone(),
+                        mark.clone(),
+                    )
+                    .await?;
+                }
+            }
+        }
+        if self.fetch_places && file.exif.position().is_some() {
+            self.overpass.update_image_places(pool, photo.id).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Inotify watches for a directory tree, and files changed in it.
+struct Watcher {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    /// Changed files, with the time of the last change.
+    pending: HashMap<PathBuf, Instant>,
+}
+
+impl Watcher {
+    fn new() -> io::Result<Self> {
+        Ok(Watcher {
+            inotify: Inotify::init()?,
+            dirs: HashMap::new(),
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Watch `dir` and all directories below it.
+    ///
+    /// If `seen` is given, the files found are handled as changed at
+    /// that time, which is used for directories moved into the tree.
+    fn add_tree(
+        &mut self,
+        dir: &Path,
+        seen: Option<Instant>,
+    ) -> io::Result<()> {
+        let mask = WatchMask::CREATE
+            | WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::MOVED_TO
+            | WatchMask::MOVED_FROM
+            | WatchMask::DELETE;
+        let wd = self.inotify.add_watch(dir, mask)?;
+        self.dirs.insert(wd, dir.into());
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if fs::metadata(&path)?.is_dir() {
+                self.add_tree(&path, seen)?;
+            } else if let Some(seen) = seen {
+                self.pending.insert(path, seen);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read all available events, without blocking.
+    fn read_events(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = self
+                .inotify
+                .read_events(&mut buffer)?
+                .map(|e| (e.wd, e.mask, e.name.map(PathBuf::from)))
+                .collect::<Vec<_>>();
+            if events.is_empty() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            for (wd, mask, name) in events {
+                if mask.contains(EventMask::Q_OVERFLOW) {
+                    warn!(
+                        "Inotify queue overflow, run findphotos to catch up"
+                    );
+                }
+                if mask.contains(EventMask::IGNORED) {
+                    self.dirs.remove(&wd);
+                }
+                let path = match (self.dirs.get(&wd), name) {
+                    (Some(dir), Some(name)) => dir.join(name),
+                    _ => continue,
+                };
+                if mask.contains(EventMask::ISDIR) {
+                    if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                    {
+                        debug!("New directory {}", path.display());
+                        if let Err(e) = self.add_tree(&path, Some(now)) {
+                            warn!("Failed to watch {}: {}", path.display(), e);
+                        }
+                    }
+                } else if mask
+                    .intersects(EventMask::MOVED_FROM | EventMask::DELETE)
+                {
+                    self.pending.remove(&path);
+                } else {
+                    self.pending.insert(path, now);
+                }
+            }
+        }
+    }
+
+    /// Take the files that has not changed for the `settle` duration.
+    fn settled(&mut self, settle: Duration) -> Vec<PathBuf> {
+        let ready = self
+            .pending
+            .iter()
+            .filter(|(_, changed)| changed.elapsed() >= settle)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in &ready {
+            self.pending.remove(path);
+        }
+        ready
+    }
+}
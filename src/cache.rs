@@ -0,0 +1,436 @@
+//! Caches for scaled images.
+//!
+//! Scaling an image takes a while, so the server and precache keep
+//! the results in a cache.  It can be memcached, a directory on disk
+//! or the memory of the server process.
+use log::{debug, warn};
+use lru::LruCache;
+use r2d2_memcache::memcache::MemcacheError;
+use r2d2_memcache::r2d2::{self, Pool};
+use r2d2_memcache::MemcacheConnectionManager;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use std::{fmt, io};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct CacheOpt {
+    /// Where to cache scaled images: memcache, disk or memory.
+    #[structopt(long, env = "RPHOTOS_CACHE", default_value = "memcache")]
+    cache_backend: Backend,
+
+    /// How to connect to memcached.
+    #[structopt(
+        long,
+        env = "MEMCACHED_SERVER",
+        default_value = "memcache://127.0.0.1:11211"
+    )]
+    memcached_url: String,
+
+    /// Directory for the disk cache.
+    #[structopt(
+        long,
+        env = "RPHOTOS_CACHE_DIR",
+        required_if("cache-backend", "disk")
+    )]
+    cache_dir: Option<PathBuf>,
+
+    /// Max size of the disk or memory cache, in megabytes.
+    ///
+    /// When the cache gets larger, the least recently used images
+    /// are removed.  Memcached has its own limit.
+    #[structopt(long, env = "RPHOTOS_CACHE_SIZE", default_value = "256")]
+    cache_size: u64,
+}
+
+impl CacheOpt {
+    pub fn open(&self) -> Result<Box<dyn Cache>, Error> {
+        let max_size = self.cache_size << 20;
+        Ok(match self.cache_backend {
+            Backend::Memcache => Box::new(Memcache::new(&self.memcached_url)?),
+            Backend::Disk => {
+                let dir = self.cache_dir.as_ref().ok_or(Error::NoDir)?;
+                Box::new(DiskCache::open(dir, max_size)?)
+            }
+            Backend::Memory => Box::new(MemoryCache::new(max_size)),
+        })
+    }
+
+    /// Open a cache that is shared with the server.
+    ///
+    /// That is what precaching is useful for, so an in-memory cache
+    /// is an error.
+    pub fn open_shared(&self) -> Result<Box<dyn Cache>, Error> {
+        if let Backend::Memory = self.cache_backend {
+            return Err(Error::NotShared);
+        }
+        self.open()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Backend {
+    Memcache,
+    Disk,
+    Memory,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memcache" | "memcached" => Ok(Backend::Memcache),
+            "disk" => Ok(Backend::Disk),
+            "memory" => Ok(Backend::Memory),
+            _ => Err(format!(
+                "Unknown cache {:?}, try memcache, disk or memory",
+                s,
+            )),
+        }
+    }
+}
+
+/// A cache of binary data, like scaled images.
+pub trait Cache: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Store `data` as `key`, replacing any previous data.
+    ///
+    /// Only memcached expires data, the other caches keep it until
+    /// it is the least recently used when space is needed.
+    fn set(
+        &self,
+        key: &str,
+        data: &[u8],
+        expire: Option<Duration>,
+    ) -> Result<(), Error>;
+
+    /// Remove `key` from the cache.  Returns true if it was there.
+    fn delete(&self, key: &str) -> Result<bool, Error>;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Memcache(MemcacheError),
+    Pool(r2d2::Error),
+    Io(io::Error),
+    NoDir,
+    NotShared,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Memcache(e) => write!(f, "Memcached error: {}", e),
+            Error::Pool(e) => write!(f, "Memcached pool error: {}", e),
+            Error::Io(e) => write!(f, "Cache I/O error: {}", e),
+            Error::NoDir => write!(f, "A disk cache needs a directory"),
+            Error::NotShared => {
+                write!(f, "An in-memory cache is not shared with the server")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<MemcacheError> for Error {
+    fn from(e: MemcacheError) -> Self {
+        Error::Memcache(e)
+    }
+}
+impl From<r2d2::Error> for Error {
+    fn from(e: r2d2::Error) -> Self {
+        Error::Pool(e)
+    }
+}
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub struct Memcache {
+    pool: Pool<MemcacheConnectionManager>,
+}
+
+impl Memcache {
+    fn new(url: &str) -> Result<Self, Error> {
+        let pool = Pool::builder()
+            .connection_timeout(Duration::from_secs(1))
+            .build(MemcacheConnectionManager::new(url))?;
+        Ok(Memcache { pool })
+    }
+}
+
+impl Cache for Memcache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.pool.get()?.get(key)?)
+    }
+    fn set(
+        &self,
+        key: &str,
+        data: &[u8],
+        expire: Option<Duration>,
+    ) -> Result<(), Error> {
+        let no_expire = 0;
+        let expire = expire.map_or(no_expire, |e| e.as_secs() as u32);
+        Ok(self.pool.get()?.set(key, data, expire)?)
+    }
+    fn delete(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.pool.get()?.delete(key)?)
+    }
+}
+
+/// A cache in a directory, with a file for each key.
+///
+/// The least recently used files are removed when the files get
+/// larger than the max size.  The modification time of a file is
+/// updated when it is used, so the order is kept between runs.
+///
+/// The server and precache can share a directory.  Each only knows
+/// the sizes of files it has seen, so the limit is not exact then.
+pub struct DiskCache {
+    dir: PathBuf,
+    lru: Mutex<Lru<()>>,
+}
+
+impl DiskCache {
+    fn open(dir: &Path, max_size: u64) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            match entry.file_name().into_string() {
+                Ok(name) if meta.is_file() && !name.starts_with('.') => {
+                    files.push((meta.modified()?, name, meta.len()));
+                }
+                _ => (),
+            }
+        }
+        files.sort();
+        debug!("Found {} files in cache {}", files.len(), dir.display());
+        let cache = DiskCache {
+            dir: dir.into(),
+            lru: Mutex::new(Lru::new(max_size)),
+        };
+        for (_, name, size) in files {
+            cache.add(name, size);
+        }
+        Ok(cache)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        // The keys are nice ascii, but make sure of it.
+        let name = key
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' => c.to_string(),
+                c => format!("_{:x}", u32::from(c)),
+            })
+            .collect::<String>();
+        self.dir.join(name)
+    }
+
+    /// Add a file to the lru, removing the files it evicts.
+    fn add(&self, key: String, size: u64) {
+        let evicted = self.lru.lock().unwrap().insert(key, (), size);
+        for (key, ()) in evicted {
+            debug!("Cache: evicting {}", key);
+            if let Err(e) = fs::remove_file(self.path(&key)) {
+                warn!("Cache: Failed to remove {}: {}", key, e);
+            }
+        }
+    }
+}
+
+impl Cache for DiskCache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let path = self.path(key);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.lru.lock().unwrap().remove(key);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let known = self.lru.lock().unwrap().get(key).is_some();
+        if !known {
+            // Stored by another process.
+            self.add(key.into(), data.len() as u64);
+        }
+        if let Err(e) = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(SystemTime::now()))
+        {
+            debug!("Cache: Failed to touch {}: {}", key, e);
+        }
+        Ok(Some(data))
+    }
+    fn set(
+        &self,
+        key: &str,
+        data: &[u8],
+        _expire: Option<Duration>,
+    ) -> Result<(), Error> {
+        let path = self.path(key);
+        // Write and rename, so a reader never sees a partial file.
+        // The temporary name is unique, also between threads.
+        static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let tmp = self.dir.join(format!(
+            ".{}.{}.{}",
+            path.file_name().unwrap().to_string_lossy(),
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+        self.add(key.into(), data.len() as u64);
+        Ok(())
+    }
+    fn delete(&self, key: &str) -> Result<bool, Error> {
+        self.lru.lock().unwrap().remove(key);
+        match fs::remove_file(self.path(key)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A cache in the memory of this process.
+pub struct MemoryCache {
+    lru: Mutex<Lru<Vec<u8>>>,
+}
+
+impl MemoryCache {
+    fn new(max_size: u64) -> Self {
+        MemoryCache {
+            lru: Mutex::new(Lru::new(max_size)),
+        }
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.lru.lock().unwrap().get(key).cloned())
+    }
+    fn set(
+        &self,
+        key: &str,
+        data: &[u8],
+        _expire: Option<Duration>,
+    ) -> Result<(), Error> {
+        let size = data.len() as u64;
+        self.lru
+            .lock()
+            .unwrap()
+            .insert(key.into(), data.to_vec(), size);
+        Ok(())
+    }
+    fn delete(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.lru.lock().unwrap().remove(key).is_some())
+    }
+}
+
+/// Entries with sizes, in least recently used order.
+///
+/// The `lru` crate keeps the order, but limits the number of entries
+/// rather than their size, so the size is tracked beside it.
+struct Lru<V> {
+    entries: LruCache<String, (V, u64)>,
+    size: u64,
+    max_size: u64,
+}
+
+impl<V> Lru<V> {
+    fn new(max_size: u64) -> Self {
+        Lru {
+            entries: LruCache::unbounded(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    /// Insert an entry, returning the entries evicted to make room.
+    fn insert(
+        &mut self,
+        key: String,
+        value: V,
+        size: u64,
+    ) -> Vec<(String, V)> {
+        if let Some((_, (_, old_size))) = self.entries.push(key, (value, size))
+        {
+            self.size -= old_size;
+        }
+        self.size += size;
+        let mut evicted = Vec::new();
+        while self.size > self.max_size {
+            let (key, (value, size)) = match self.entries.pop_lru() {
+                Some(entry) => entry,
+                None => break,
+            };
+            self.size -= size;
+            evicted.push((key, value));
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let (value, size) = self.entries.pop(key)?;
+        self.size -= size;
+        Some(value)
+    }
+}
+
+#[test]
+fn lru_evicts_least_recently_used() {
+    let mut lru = Lru::new(10);
+    assert!(lru.insert("a".into(), 1, 4).is_empty());
+    assert!(lru.insert("b".into(), 2, 4).is_empty());
+    assert_eq!(lru.get("a"), Some(&1));
+    assert_eq!(lru.insert("c".into(), 3, 4), vec![("b".into(), 2)]);
+    assert_eq!(lru.get("b"), None);
+    assert_eq!(lru.insert("a".into(), 4, 2), vec![]);
+    assert_eq!(lru.size, 6);
+    assert_eq!(lru.remove("c"), Some(3));
+    assert_eq!(
+        lru.insert("d".into(), 5, 11),
+        vec![("a".into(), 4), ("d".into(), 5)]
+    );
+    assert_eq!(lru.size, 0);
+}
+
+#[test]
+fn disk_cache() {
+    let dir = std::env::temp_dir()
+        .join(format!("rphotos-cache-test-{}", std::process::id()));
+    let cache = DiskCache::open(&dir, 10).unwrap();
+    cache.set("rp1Small", b"small", None).unwrap();
+    cache.set("rp1Medium", b"medium", None).unwrap();
+    assert_eq!(cache.get("rp1Small").unwrap(), None);
+    assert_eq!(cache.get("rp1Medium").unwrap(), Some(b"medium".to_vec()));
+    let reopened = DiskCache::open(&dir, 10).unwrap();
+    assert_eq!(reopened.get("rp1Medium").unwrap(), Some(b"medium".to_vec()));
+    assert!(reopened.delete("rp1Medium").unwrap());
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| cache.set("rp1Small", b"small", None).unwrap());
+        }
+    });
+    assert_eq!(cache.get("rp1Small").unwrap(), Some(b"small".to_vec()));
+    assert_eq!(cache.get("rp1Medium").unwrap(), None);
+    fs::remove_dir_all(&dir).unwrap();
+}
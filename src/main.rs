@@ -4,18 +4,27 @@
 extern crate diesel;
 
 mod adm;
+mod cache;
 mod dbopt;
 mod fetch_places;
+mod metadata;
 mod models;
+mod mp4;
 mod myexif;
 mod photosdir;
 mod pidfiles;
 mod schema;
 mod server;
+mod watermark;
+mod xmp;
 
 use crate::adm::result::Error;
 use crate::adm::stats::show_stats;
-use crate::adm::{findphotos, makepublic, precache, storestatics, users};
+use crate::adm::{
+    attribution, copyposition, duplicates, exportxmp, findphotos, geotag,
+    makepublic, orphans, precache, shifttime, storestatics, users, watch,
+};
+use crate::cache::CacheOpt;
 use crate::dbopt::DbOpt;
 use dotenv::dotenv;
 use std::path::PathBuf;
@@ -26,20 +35,55 @@ use structopt::StructOpt;
 #[derive(StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 enum RPhotos {
+    /// Show or set the default attribution of cameras.
+    ///
+    /// Photos from a camera get its default attribution when there is
+    /// no artist or copyright in the file.
+    CameraAttribution(attribution::CameraAttribution),
+    /// Copy positions to photos from photos taken close in time.
+    ///
+    /// Useful when a camera without gps is used together with a
+    /// phone that has it.
+    CopyPositions(copyposition::CopyPositions),
     /// Make specific image(s) public.
     ///
     /// The image path(s) are relative to the image root.
     Makepublic(makepublic::Makepublic),
+    /// List groups of photos that look the same.
+    ///
+    /// Photos are compared by the perceptual hash stored by
+    /// findphotos.
+    Duplicates(duplicates::Duplicates),
+    /// Write xmp sidecars with tags, people, grade and places.
+    ///
+    /// The sidecars are written to a separate directory, mirroring the
+    /// photo directory, since the photo directory may be read-only.
+    ExportXmp(exportxmp::ExportXmp),
     /// Get place tags for photos by looking up coordinates in OSM
     Fetchplaces(fetch_places::Fetchplaces),
     /// Find new photos in the photo directory
     Findphotos(findphotos::Findphotos),
+    /// Set positions of photos from gpx tracks.
+    ///
+    /// Photos without a position, taken while the track was recorded,
+    /// get a position interpolated from the nearest track points.
+    Geotag(geotag::Geotag),
+    /// List photos whose file is gone from the photo directory.
+    ///
+    /// The orphaned photos can optionally be marked as missing or
+    /// deleted.
+    Orphans(orphans::Orphans),
     /// Make sure the photos has thumbnails stored in cache.
     ///
     /// The time limit is checked after each stored image, so the
     /// command will complete in slightly more than the max time and
     /// one image will be processed even if the max time is zero.
     Precache(precache::Args),
+    /// Shift the time of photos, to correct a wrong camera clock.
+    ///
+    /// The shift is remembered for each photo, so it is applied again
+    /// when findphotos reads the time from the file.
+    ShiftTime(shifttime::ShiftTime),
     /// Show some statistics from the database
     Stats(DbOpt),
     /// Store statics as files for a web server
@@ -62,18 +106,10 @@ enum RPhotos {
     },
     /// Run the rphotos web server.
     Runserver(server::Args),
-}
-
-#[derive(StructOpt)]
-#[structopt(rename_all = "kebab-case")]
-struct CacheOpt {
-    /// How to connect to memcached.
-    #[structopt(
-        long,
-        env = "MEMCACHED_SERVER",
-        default_value = "memcache://127.0.0.1:11211"
-    )]
-    memcached_url: String,
+    /// Watch the photo directory and save photos as they are written.
+    ///
+    /// Uses inotify, so this only works on Linux.
+    Watch(watch::Watch),
 }
 
 #[derive(StructOpt)]
@@ -99,15 +135,23 @@ async fn main() {
 
 async fn run(args: &RPhotos) -> Result<(), Error> {
     match args {
+        RPhotos::CameraAttribution(cmd) => cmd.run(),
+        RPhotos::CopyPositions(cmd) => cmd.run().await,
+        RPhotos::Duplicates(cmd) => cmd.run(),
+        RPhotos::ExportXmp(cmd) => cmd.run(),
         RPhotos::Findphotos(cmd) => cmd.run(),
         RPhotos::Makepublic(cmd) => cmd.run(),
+        RPhotos::Orphans(cmd) => cmd.run(),
         RPhotos::Stats(db) => show_stats(&db.connect()?),
         RPhotos::Userlist { db } => users::list(&db.connect()?),
         RPhotos::Userpass { db, user } => users::passwd(&db.connect()?, user),
         RPhotos::Fetchplaces(cmd) => cmd.run().await,
+        RPhotos::Geotag(cmd) => cmd.run().await,
         RPhotos::Precache(cmd) => cmd.run().await,
+        RPhotos::ShiftTime(cmd) => cmd.run(),
         RPhotos::Storestatics { dir } => storestatics::to_dir(dir),
         RPhotos::Runserver(ra) => server::run(ra).await,
+        RPhotos::Watch(cmd) => cmd.run().await,
     }
 }
 
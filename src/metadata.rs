@@ -0,0 +1,350 @@
+//! Metadata in images served to others.
+//!
+//! Scaled images may be public, so they only get metadata that is
+//! shown on the public pages anyway, never positions or camera
+//! serial numbers.  Originals served to anonymous users get the
+//! private parts removed.
+use crate::models::Photo;
+use crate::xmp::XmpData;
+use diesel::pg::PgConnection;
+use diesel::result::Error;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(Clone, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct MetadataOpt {
+    /// Metadata to embed in scaled images.
+    ///
+    /// Any of attribution, date and description, or none.  Cached
+    /// images keep the metadata they were made with.
+    #[structopt(
+        long,
+        env = "RPHOTOS_EMBED_METADATA",
+        default_value = "attribution,date"
+    )]
+    embed_metadata: Fields,
+}
+
+impl MetadataOpt {
+    /// The xmp packet to embed in scaled images of `photo`, if any.
+    pub fn public_xmp(
+        &self,
+        db: &PgConnection,
+        photo: &Photo,
+    ) -> Result<Option<String>, Error> {
+        let mut xmp = XmpData::default();
+        for field in &self.embed_metadata.0 {
+            match field {
+                Field::Attribution => {
+                    if let Some(name) = photo.load_attribution(db) {
+                        xmp.rights = Some(match photo.date {
+                            Some(date) => {
+                                format!("© {} {}", date.format("%Y"), name)
+                            }
+                            None => format!("© {}", name),
+                        });
+                        xmp.creator = Some(name);
+                    }
+                }
+                Field::Date => {
+                    xmp.date_created = photo.date.map(|date| {
+                        let offset = photo.offset();
+                        format!(
+                            "{}{}",
+                            date.format("%FT%T"),
+                            offset.map(|o| o.to_string()).unwrap_or_default(),
+                        )
+                    });
+                }
+                Field::Description => {
+                    xmp.description = description(db, photo)?;
+                }
+            }
+        }
+        Ok(Some(xmp)
+            .filter(|xmp| *xmp != XmpData::default())
+            .map(|xmp| xmp.to_xml()))
+    }
+}
+
+/// People, tags and the most specific place, as on the details page.
+fn description(
+    db: &PgConnection,
+    photo: &Photo,
+) -> Result<Option<String>, Error> {
+    let parts = photo
+        .load_people(db)?
+        .into_iter()
+        .map(|p| p.person_name)
+        .chain(
+            photo
+                .load_tags(db)?
+                .into_iter()
+                .map(|t| format!("#{}", t.tag_name)),
+        )
+        .chain(
+            photo
+                .load_places(db)?
+                .into_iter()
+                .next()
+                .map(|p| p.place_name),
+        )
+        .collect::<Vec<_>>();
+    Ok(Some(parts.join(", ")).filter(|s| !s.is_empty()))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Attribution,
+    Date,
+    Description,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Fields(Vec<Field>);
+
+impl FromStr for Fields {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "none" {
+            return Ok(Fields(Vec::new()));
+        }
+        s.split(',')
+            .map(|field| match field.trim() {
+                "attribution" => Ok(Field::Attribution),
+                "date" => Ok(Field::Date),
+                "description" => Ok(Field::Description),
+                other => Err(format!(
+                    "Unknown metadata {:?}, \
+                     try attribution, date, description or none",
+                    other,
+                )),
+            })
+            .collect::<Result<_, _>>()
+            .map(Fields)
+    }
+}
+
+/// Remove private metadata from a jpeg image.
+///
+/// Gps data, camera and lens serial numbers and maker notes (which
+/// often contain serial numbers) are erased from the exif data,
+/// keeping the rest, like the orientation.  Exif data that can't be
+/// parsed is removed entirely, and so is any xmp packet.
+///
+/// Returns None if `jpeg` is not a jpeg image.
+pub fn strip_private(jpeg: &[u8]) -> Option<Vec<u8>> {
+    const EXIF: &[u8] = b"Exif\0\0";
+    const XMP: &[u8] = b"http://ns.adobe.com/";
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut result = Vec::with_capacity(jpeg.len());
+    result.extend_from_slice(&jpeg[..2]);
+    let mut pos = 2;
+    loop {
+        let head = jpeg.get(pos..pos + 4)?;
+        if head[0] != 0xFF {
+            return None;
+        }
+        // Image data starts at start of scan, no metadata after that.
+        if head[1] == 0xDA {
+            result.extend_from_slice(&jpeg[pos..]);
+            return Some(result);
+        }
+        let end =
+            pos + 2 + usize::from(u16::from_be_bytes([head[2], head[3]]));
+        let data = jpeg.get(pos + 4..end)?;
+        if head[1] == 0xE1 && data.starts_with(EXIF) {
+            let mut tiff = data[EXIF.len()..].to_vec();
+            if scrub_exif(&mut tiff).is_some() {
+                result.extend_from_slice(head);
+                result.extend_from_slice(EXIF);
+                result.extend_from_slice(&tiff);
+            }
+        } else if !(head[1] == 0xE1 && data.starts_with(XMP)) {
+            result.extend_from_slice(&jpeg[pos..end]);
+        }
+        pos = end;
+    }
+}
+
+const GPS_IFD: u16 = 0x8825;
+const EXIF_IFD: u16 = 0x8769;
+const MAKER_NOTE: u16 = 0x927C;
+const BODY_SERIAL_NUMBER: u16 = 0xA431;
+const LENS_SERIAL_NUMBER: u16 = 0xA435;
+
+/// Erase the private parts of tiff-formatted exif data, in place.
+fn scrub_exif(data: &mut [u8]) -> Option<()> {
+    let mut tiff = Tiff {
+        big_endian: match data.get(..2)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        },
+        data,
+    };
+    let ifd0 = tiff.u32(4)?;
+    for entry in tiff.entries(ifd0)? {
+        match tiff.u16(entry)? {
+            GPS_IFD => tiff.erase_ifd(tiff.u32(entry + 8)?)?,
+            EXIF_IFD => {
+                let exif = tiff.u32(entry + 8)?;
+                for entry in tiff.entries(exif)? {
+                    if let MAKER_NOTE | BODY_SERIAL_NUMBER
+                    | LENS_SERIAL_NUMBER = tiff.u16(entry)?
+                    {
+                        tiff.erase_value(entry)?;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Some(())
+}
+
+struct Tiff<'a> {
+    data: &'a mut [u8],
+    big_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn u16(&self, pos: usize) -> Option<u16> {
+        let bytes = [*self.data.get(pos)?, *self.data.get(pos + 1)?];
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+    fn u32(&self, pos: usize) -> Option<usize> {
+        let (a, b) = (self.u16(pos)?, self.u16(pos + 2)?);
+        let (high, low) = if self.big_endian { (a, b) } else { (b, a) };
+        Some((usize::from(high) << 16) | usize::from(low))
+    }
+
+    /// The positions of the entries of the ifd at `pos`.
+    fn entries(&self, pos: usize) -> Option<Vec<usize>> {
+        let n = usize::from(self.u16(pos)?);
+        self.data.get(pos..pos + 2 + 12 * n)?;
+        Some((0..n).map(|i| pos + 2 + 12 * i).collect())
+    }
+
+    /// Erase all values of the ifd at `pos` and make it empty.
+    fn erase_ifd(&mut self, pos: usize) -> Option<()> {
+        let entries = self.entries(pos)?;
+        for entry in &entries {
+            self.erase_value(*entry)?;
+        }
+        // Also clears the next ifd link, now right after the count.
+        self.zero(pos, 2 + 12 * entries.len() + 4)
+    }
+
+    /// Erase the value of the entry at `pos`.
+    fn erase_value(&mut self, pos: usize) -> Option<()> {
+        let size = match self.u16(pos + 2)? {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        } * self.u32(pos + 4)?;
+        if size <= 4 {
+            self.zero(pos + 8, 4)
+        } else {
+            self.zero(self.u32(pos + 8)?, size)
+        }
+    }
+
+    fn zero(&mut self, pos: usize, len: usize) -> Option<()> {
+        self.data.get_mut(pos..pos + len)?.fill(0);
+        Some(())
+    }
+}
+
+#[test]
+fn parse_fields() {
+    use Field::*;
+    let parse = |s: &str| s.parse::<Fields>().map(|f| f.0);
+    assert_eq!(parse("attribution,date"), Ok(vec![Attribution, Date]));
+    assert_eq!(parse("description"), Ok(vec![Description]));
+    assert_eq!(parse("none"), Ok(vec![]));
+    assert!(parse("gps").is_err());
+}
+
+#[test]
+fn strip_gps_and_serial() {
+    use exif::{In, Reader, Tag};
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    // Little endian tiff, with ifd0 at 8, exif ifd at 50 with its
+    // value at 68, and gps ifd at 76 with its value at 106.
+    let mut tiff = b"II\x2a\0\x08\0\0\0\x03\0".to_vec();
+    let mut entry = |tag: u16, typ: u16, count: u32, value: u32| {
+        tiff.extend_from_slice(&tag.to_le_bytes());
+        tiff.extend_from_slice(&typ.to_le_bytes());
+        tiff.extend_from_slice(&count.to_le_bytes());
+        tiff.extend_from_slice(&value.to_le_bytes());
+    };
+    entry(0x0112, 3, 1, 6); // Orientation
+    entry(EXIF_IFD, 4, 1, 50);
+    entry(GPS_IFD, 4, 1, 76);
+    tiff.extend_from_slice(&[0; 4]);
+    assert_eq!(tiff.len(), 50);
+    tiff.extend_from_slice(&[1, 0]);
+    tiff.extend_from_slice(&BODY_SERIAL_NUMBER.to_le_bytes());
+    tiff.extend_from_slice(&[2, 0, 8, 0, 0, 0, 68, 0, 0, 0, 0, 0, 0, 0]);
+    tiff.extend_from_slice(b"SN12345\0");
+    assert_eq!(tiff.len(), 76);
+    tiff.extend_from_slice(&[2, 0, 1, 0, 2, 0, 2, 0, 0, 0, b'N', 0, 0, 0]);
+    tiff.extend_from_slice(&[2, 0, 5, 0, 3, 0, 0, 0, 106, 0, 0, 0]);
+    tiff.extend_from_slice(&[0; 4]);
+    for (value, denom) in &[(59, 1), (19, 1), (4307, 100)] {
+        tiff.extend_from_slice(&u32::to_le_bytes(*value));
+        tiff.extend_from_slice(&u32::to_le_bytes(*denom));
+    }
+    assert_eq!(tiff.len(), 130);
+
+    let mut image = Vec::new();
+    DynamicImage::ImageRgb8(RgbImage::new(8, 8))
+        .write_to(&mut image, ImageFormat::Jpeg)
+        .unwrap();
+    let segment = |data: &[u8]| {
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    };
+    let mut jpeg = vec![0xFF, 0xD8];
+    jpeg.extend(segment(&[&b"Exif\0\0"[..], &tiff].concat()));
+    let xmp = segment(b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>");
+    jpeg.extend_from_slice(&xmp);
+    jpeg.extend_from_slice(&image[2..]);
+
+    let read = |jpeg: &[u8]| {
+        Reader::new()
+            .read_from_container(&mut std::io::Cursor::new(jpeg))
+            .unwrap()
+    };
+    let exif = read(&jpeg);
+    assert!(exif.get_field(Tag::GPSLatitude, In::PRIMARY).is_some());
+    assert!(exif.get_field(Tag::BodySerialNumber, In::PRIMARY).is_some());
+
+    let stripped = strip_private(&jpeg).unwrap();
+    assert_eq!(stripped.len(), jpeg.len() - xmp.len());
+    image::load_from_memory(&stripped).unwrap();
+    let exif = read(&stripped);
+    let orientation = exif.get_field(Tag::Orientation, In::PRIMARY);
+    let orientation = orientation.and_then(|f| f.value.get_uint(0));
+    assert_eq!(orientation, Some(6));
+    assert!(exif.get_field(Tag::GPSLatitude, In::PRIMARY).is_none());
+    let serial = exif.get_field(Tag::BodySerialNumber, In::PRIMARY);
+    assert!(!format!("{:?}", serial).contains("SN12345"));
+    let find =
+        |needle: &[u8]| stripped.windows(needle.len()).any(|w| w == needle);
+    assert!(!find(b"SN12345"));
+    assert!(!find(b"xmpmeta"));
+    assert!(!find(&u32::to_le_bytes(4307)));
+}
@@ -1,6 +1,10 @@
+use crate::photosdir::{FileFormat, Fingerprint, SCALER_VERSION};
+use crate::schema::attributions;
 use crate::schema::attributions::dsl as a;
 use crate::schema::cameras;
 use crate::schema::cameras::dsl as c;
+use crate::schema::lenses;
+use crate::schema::lenses::dsl as le;
 use crate::schema::people::dsl as h;
 use crate::schema::photo_people::dsl as ph;
 use crate::schema::photo_places::dsl as pl;
@@ -10,7 +14,9 @@ use crate::schema::photos::dsl as p;
 use crate::schema::places::dsl as l;
 use crate::schema::positions::dsl as pos;
 use crate::schema::tags::dsl as t;
+use crate::watermark::Mark;
 use chrono::naive::NaiveDateTime;
+use chrono::{Duration, FixedOffset, Local, TimeZone};
 use diesel::pg::{Pg, PgConnection};
 use diesel::prelude::*;
 use diesel::result::Error;
@@ -18,6 +24,8 @@ use diesel::sql_types::Integer;
 use log::error;
 use slug::slugify;
 use std::cmp::max;
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
 pub struct Photo {
@@ -31,6 +39,43 @@ pub struct Photo {
     pub attribution_id: Option<i32>,
     pub width: i32,
     pub height: i32,
+    pub file_size: Option<i64>,
+    pub file_mtime: Option<NaiveDateTime>,
+    pub file_inode: Option<i64>,
+    pub content_hash: Option<String>,
+    pub is_missing: bool,
+    /// For a raw file, the id of the jpeg photo made from it.
+    pub raw_of: Option<i32>,
+    /// Perceptual hash of the image, see [`crate::photosdir::dhash`].
+    pub dhash: Option<i64>,
+    /// File format, as a file name extension, e.g. "jpg" or "png".
+    pub format: Option<String>,
+    pub mime_type: Option<String>,
+    /// For a video, the length in milliseconds.
+    pub duration: Option<i32>,
+    /// Offset of `date` from UTC in seconds, if known.
+    ///
+    /// The date is the local time where the photo was taken.
+    pub utc_offset: Option<i32>,
+    /// Seconds that `date` is shifted from the time in the file.
+    pub time_shift: i32,
+    pub lens_id: Option<i32>,
+    /// Exposure time in seconds.
+    pub exposure_time: Option<f32>,
+    pub f_number: Option<f32>,
+    pub iso: Option<i32>,
+    /// Focal length in mm.
+    pub focal_length: Option<f32>,
+    /// True if the flash fired.
+    pub flash: Option<bool>,
+    /// Mirror the image horizontally, before the rotation.
+    pub flip: bool,
+    /// Size of the xmp sidecar, as last imported.
+    pub xmp_size: Option<i64>,
+    /// Modification time of the xmp sidecar, as last imported.
+    pub xmp_mtime: Option<NaiveDateTime>,
+    /// True if no perceptual hash could be made from the file.
+    pub dhash_failed: bool,
 }
 
 #[derive(Debug)]
@@ -46,15 +91,79 @@ impl Photo {
         self.is_public
     }
 
-    pub fn cache_key(&self, size: SizeTag) -> String {
-        format!("rp{}{:?}", self.id, size)
+    pub fn cache_key(&self, size: SizeTag, format: ScaledFormat) -> String {
+        format!("rp{}{:?}{}", self.id, size, format.ext())
+    }
+    pub fn variant_cache_key(
+        &self,
+        variant: &SizeVariant,
+        format: ScaledFormat,
+    ) -> String {
+        format!("rp{}x{}{}", self.id, variant.px, format.ext())
+    }
+    /// The cache key for the version of an image with `mark`.
+    pub fn watermarked_key(key: &str, mark: &Mark) -> String {
+        format!("{}w{}", key, mark.key())
+    }
+
+    /// The version of the images of this photo.
+    ///
+    /// It changes with the file, the orientation and the scaling, and
+    /// is used in image urls and etags so browsers don't keep old
+    /// images.
+    pub fn image_version(&self) -> String {
+        let hash = self.content_hash.as_deref().unwrap_or_default();
+        format!(
+            "{}-{}{}-{}",
+            hash.get(..8).unwrap_or("0"),
+            self.rotation,
+            if self.flip { "m" } else { "" },
+            SCALER_VERSION,
+        )
+    }
+
+    /// File name extension for the original file.
+    ///
+    /// Photos that findphotos has not yet detected the format of are
+    /// assumed to be jpeg, as most photos are.
+    pub fn extension(&self) -> &str {
+        self.format.as_deref().unwrap_or("jpg")
+    }
+
+    /// Content type for the original file, see [`Photo::extension`].
+    pub fn content_type(&self) -> &str {
+        self.mime_type.as_deref().unwrap_or("image/jpeg")
+    }
+
+    /// The offset of the date from UTC, if known.
+    pub fn offset(&self) -> Option<FixedOffset> {
+        self.utc_offset.and_then(FixedOffset::east_opt)
+    }
+
+    /// When the photo was taken, in UTC.
+    ///
+    /// A photo without a known utc offset is taken to be in the
+    /// timezone of this host.
+    pub fn utc_date(&self) -> Option<NaiveDateTime> {
+        let date = self.date?;
+        match self.utc_offset {
+            Some(offset) => Some(date - Duration::seconds(offset.into())),
+            None => Local
+                .from_local_datetime(&date)
+                .earliest()
+                .map(|local| local.naive_utc()),
+        }
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.content_type().starts_with("video/")
     }
 
     #[allow(dead_code)]
     pub fn query<'a>(auth: bool) -> photos::BoxedQuery<'a, Pg> {
         let result = p::photos
-            .filter(p::path.not_like("%.CR2"))
-            .filter(p::path.not_like("%.dng"))
+            .filter(p::raw_of.is_null())
+            .filter(p::is_missing.eq(false))
             .into_boxed();
         if !auth {
             result.filter(p::is_public)
@@ -63,12 +172,19 @@ impl Photo {
         }
     }
 
+    /// Update the photo at `file_path`, if there is one.
+    ///
+    /// The `time_shift` of the photo is added to `exifdate` if that is
+    /// from the camera clock, since a date from GPS is already right.
+    #[allow(clippy::too_many_arguments)]
     pub fn update_by_path(
         db: &PgConnection,
         file_path: &str,
         newwidth: i32,
         newheight: i32,
         exifdate: Option<NaiveDateTime>,
+        utc_offset: Option<i32>,
+        date_from_gps: bool,
         camera: &Option<Camera>,
     ) -> Result<Option<Modification<Photo>>, Error> {
         if let Some(mut pic) = p::photos
@@ -77,6 +193,11 @@ impl Photo {
             .optional()?
         {
             let mut change = false;
+            let exifdate = if date_from_gps {
+                exifdate
+            } else {
+                exifdate.map(|d| d + Duration::seconds(pic.time_shift.into()))
+            };
             // TODO Merge updates to one update statement!
             if pic.width != newwidth || pic.height != newheight {
                 change = true;
@@ -84,10 +205,12 @@ impl Photo {
                     .set((p::width.eq(newwidth), p::height.eq(newheight)))
                     .get_result::<Photo>(db)?;
             }
-            if exifdate.is_some() && exifdate != pic.date {
+            if exifdate.is_some()
+                && (exifdate != pic.date || utc_offset != pic.utc_offset)
+            {
                 change = true;
                 pic = diesel::update(p::photos.find(pic.id))
-                    .set(p::date.eq(exifdate))
+                    .set((p::date.eq(exifdate), p::utc_offset.eq(utc_offset)))
                     .get_result::<Photo>(db)?;
             }
             if let Some(ref camera) = *camera {
@@ -108,17 +231,28 @@ impl Photo {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_or_set_basics(
         db: &PgConnection,
         file_path: &str,
         newwidth: i32,
         newheight: i32,
         exifdate: Option<NaiveDateTime>,
+        utc_offset: Option<i32>,
+        date_from_gps: bool,
         exifrotation: i16,
+        flip: bool,
         camera: Option<Camera>,
     ) -> Result<Modification<Photo>, Error> {
         if let Some(result) = Self::update_by_path(
-            db, file_path, newwidth, newheight, exifdate, &camera,
+            db,
+            file_path,
+            newwidth,
+            newheight,
+            exifdate,
+            utc_offset,
+            date_from_gps,
+            &camera,
         )? {
             Ok(result)
         } else {
@@ -126,7 +260,9 @@ impl Photo {
                 .values((
                     p::path.eq(file_path),
                     p::date.eq(exifdate),
+                    p::utc_offset.eq(utc_offset),
                     p::rotation.eq(exifrotation),
+                    p::flip.eq(flip),
                     p::width.eq(newwidth),
                     p::height.eq(newheight),
                     p::camera_id.eq(camera.map(|c| c.id)),
@@ -136,6 +272,162 @@ impl Photo {
         }
     }
 
+    /// The fingerprint of the file as it was when last read, if known.
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        Some(Fingerprint {
+            size: self.file_size?,
+            mtime: self.file_mtime?,
+            inode: self.file_inode,
+        })
+    }
+
+    /// The fingerprint of the xmp sidecar as it was when last
+    /// imported, if any.
+    pub fn xmp_fingerprint(&self) -> Option<Fingerprint> {
+        Some(Fingerprint {
+            size: self.xmp_size?,
+            mtime: self.xmp_mtime?,
+            inode: None,
+        })
+    }
+
+    /// Store the fingerprint of the imported xmp sidecar, if any.
+    pub fn set_xmp_fingerprint(
+        self,
+        db: &PgConnection,
+        fingerprint: Option<&Fingerprint>,
+    ) -> Result<Photo, Error> {
+        if self.xmp_fingerprint().as_ref() == fingerprint {
+            return Ok(self);
+        }
+        diesel::update(p::photos.find(self.id))
+            .set((
+                p::xmp_size.eq(fingerprint.map(|f| f.size)),
+                p::xmp_mtime.eq(fingerprint.map(|f| f.mtime)),
+            ))
+            .get_result::<Photo>(db)
+    }
+
+    /// Store if making a perceptual hash of the file failed.
+    pub fn set_dhash_failed(
+        self,
+        db: &PgConnection,
+        failed: bool,
+    ) -> Result<Photo, Error> {
+        if self.dhash_failed == failed {
+            return Ok(self);
+        }
+        diesel::update(p::photos.find(self.id))
+            .set(p::dhash_failed.eq(failed))
+            .get_result::<Photo>(db)
+    }
+
+    /// Store the fingerprint, content hash and perceptual hash of the
+    /// file.
+    ///
+    /// Since the file is evidently found, it is not missing.
+    pub fn set_file_info(
+        self,
+        db: &PgConnection,
+        fingerprint: &Fingerprint,
+        hash: &str,
+        dhash: Option<i64>,
+        format: Option<FileFormat>,
+        duration: Option<i32>,
+    ) -> Result<Photo, Error> {
+        if self.fingerprint().as_ref() == Some(fingerprint)
+            && self.content_hash.as_deref() == Some(hash)
+            && self.dhash == dhash
+            && self.format.as_deref() == format.map(|f| f.format)
+            && self.mime_type.as_deref() == format.map(|f| f.mime)
+            && self.duration == duration
+            && !self.is_missing
+        {
+            return Ok(self);
+        }
+        diesel::update(p::photos.find(self.id))
+            .set((
+                p::file_size.eq(fingerprint.size),
+                p::file_mtime.eq(fingerprint.mtime),
+                p::file_inode.eq(fingerprint.inode),
+                p::content_hash.eq(hash),
+                p::dhash.eq(dhash),
+                p::format.eq(format.map(|f| f.format)),
+                p::mime_type.eq(format.map(|f| f.mime)),
+                p::duration.eq(duration),
+                p::is_missing.eq(false),
+            ))
+            .get_result::<Photo>(db)
+    }
+
+    /// The exposure settings of this photo.
+    pub fn exposure(&self) -> Exposure {
+        Exposure {
+            exposure_time: self.exposure_time,
+            f_number: self.f_number,
+            iso: self.iso,
+            focal_length: self.focal_length,
+            flash: self.flash,
+        }
+    }
+
+    /// Store exposure settings and lens, as read from the file.
+    pub fn set_exposure(
+        self,
+        db: &PgConnection,
+        exposure: &Exposure,
+        lens: Option<&Lens>,
+    ) -> Result<Modification<Photo>, Error> {
+        let lens_id = lens.map(|l| l.id);
+        if self.exposure() == *exposure && self.lens_id == lens_id {
+            return Ok(Modification::Unchanged(self));
+        }
+        Ok(Modification::Updated(
+            diesel::update(p::photos.find(self.id))
+                .set((
+                    p::lens_id.eq(lens_id),
+                    p::exposure_time.eq(exposure.exposure_time),
+                    p::f_number.eq(exposure.f_number),
+                    p::iso.eq(exposure.iso),
+                    p::focal_length.eq(exposure.focal_length),
+                    p::flash.eq(exposure.flash),
+                ))
+                .get_result::<Photo>(db)?,
+        ))
+    }
+
+    /// Set the attribution of this photo.
+    pub fn set_attribution(
+        self,
+        db: &PgConnection,
+        attribution_id: i32,
+    ) -> Result<Modification<Photo>, Error> {
+        if self.attribution_id == Some(attribution_id) {
+            return Ok(Modification::Unchanged(self));
+        }
+        Ok(Modification::Updated(
+            diesel::update(p::photos.find(self.id))
+                .set(p::attribution_id.eq(attribution_id))
+                .get_result::<Photo>(db)?,
+        ))
+    }
+
+    /// Mark this photo as the raw file of the photo `jpeg`.
+    pub fn set_raw_of(
+        self,
+        db: &PgConnection,
+        jpeg: i32,
+    ) -> Result<Modification<Photo>, Error> {
+        if self.raw_of == Some(jpeg) {
+            return Ok(Modification::Unchanged(self));
+        }
+        Ok(Modification::Updated(
+            diesel::update(p::photos.find(self.id))
+                .set(p::raw_of.eq(jpeg))
+                .get_result::<Photo>(db)?,
+        ))
+    }
+
     pub fn load_people(
         &self,
         db: &PgConnection,
@@ -198,9 +490,23 @@ impl Photo {
         self.camera_id
             .and_then(|i| c::cameras.find(i).first(db).ok())
     }
+    pub fn load_lens(&self, db: &PgConnection) -> Option<Lens> {
+        self.lens_id.and_then(|i| le::lenses.find(i).first(db).ok())
+    }
+    pub fn load_raw(&self, db: &PgConnection) -> Option<Photo> {
+        p::photos
+            .filter(p::raw_of.eq(self.id))
+            .filter(p::is_missing.eq(false))
+            .first(db)
+            .ok()
+    }
     pub fn get_size(&self, size: SizeTag) -> (u32, u32) {
+        self.scaled_size(size.px())
+    }
+    /// The size of this photo scaled to at most `px` in each direction.
+    pub fn scaled_size(&self, px: u32) -> (u32, u32) {
         let (width, height) = (self.width, self.height);
-        let scale = f64::from(size.px()) / f64::from(max(width, height));
+        let scale = f64::from(px) / f64::from(max(width, height));
         let w = (scale * f64::from(width)) as u32;
         let h = (scale * f64::from(height)) as u32;
         match self.rotation {
@@ -227,6 +533,28 @@ impl Photo {
             attribution_id: None,
             width: 4000,
             height: 3000,
+            file_size: None,
+            file_mtime: None,
+            file_inode: None,
+            content_hash: None,
+            is_missing: false,
+            raw_of: None,
+            dhash: None,
+            format: Some("jpg".into()),
+            mime_type: Some("image/jpeg".into()),
+            duration: None,
+            utc_offset: None,
+            time_shift: 0,
+            lens_id: None,
+            exposure_time: None,
+            f_number: None,
+            iso: None,
+            focal_length: None,
+            flash: None,
+            flip: false,
+            xmp_size: None,
+            xmp_mtime: None,
+            dhash_failed: false,
         }
     }
 }
@@ -244,6 +572,22 @@ pub struct Tag {
     pub tag_name: String,
 }
 
+impl Tag {
+    pub fn get_or_create_name(
+        db: &PgConnection,
+        name: &str,
+    ) -> Result<Tag, Error> {
+        t::tags
+            .filter(t::tag_name.ilike(name))
+            .first(db)
+            .or_else(|_| {
+                diesel::insert_into(t::tags)
+                    .values((t::tag_name.eq(name), t::slug.eq(&slugify(name))))
+                    .get_result(db)
+            })
+    }
+}
+
 impl Facet for Tag {
     fn by_slug(slug: &str, db: &PgConnection) -> Result<Tag, Error> {
         t::tags.filter(t::slug.eq(slug)).first(db)
@@ -323,6 +667,8 @@ pub struct Camera {
     pub id: i32,
     pub manufacturer: String,
     pub model: String,
+    /// Default attribution for photos without one in the file.
+    pub attribution_id: Option<i32>,
 }
 
 impl Camera {
@@ -346,6 +692,134 @@ impl Camera {
     }
 }
 
+#[derive(Debug, Clone, Identifiable, Queryable)]
+pub struct Attribution {
+    pub id: i32,
+    pub name: String,
+}
+
+impl Attribution {
+    pub fn get_or_create(
+        db: &PgConnection,
+        name: &str,
+    ) -> Result<Attribution, Error> {
+        if let Some(attribution) = a::attributions
+            .filter(a::name.eq(name))
+            .first::<Attribution>(db)
+            .optional()?
+        {
+            Ok(attribution)
+        } else {
+            diesel::insert_into(a::attributions)
+                .values(a::name.eq(name))
+                .get_result(db)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "lenses"]
+pub struct Lens {
+    pub id: i32,
+    pub manufacturer: String,
+    pub model: String,
+}
+
+impl Lens {
+    pub fn all(db: &PgConnection) -> Result<Vec<Lens>, Error> {
+        le::lenses.order((le::manufacturer, le::model)).load(db)
+    }
+    pub fn by_id(id: i32, db: &PgConnection) -> Result<Lens, Error> {
+        le::lenses.find(id).first(db)
+    }
+    pub fn get_or_create(
+        db: &PgConnection,
+        make: &str,
+        modl: &str,
+    ) -> Result<Lens, Error> {
+        if let Some(lens) = le::lenses
+            .filter(le::manufacturer.eq(make))
+            .filter(le::model.eq(modl))
+            .first::<Lens>(db)
+            .optional()?
+        {
+            Ok(lens)
+        } else {
+            diesel::insert_into(le::lenses)
+                .values((le::manufacturer.eq(make), le::model.eq(modl)))
+                .get_result(db)
+        }
+    }
+}
+
+/// Exposure settings of a photo.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Exposure {
+    /// Exposure time in seconds.
+    pub exposure_time: Option<f32>,
+    pub f_number: Option<f32>,
+    pub iso: Option<i32>,
+    /// Focal length in mm.
+    pub focal_length: Option<f32>,
+    /// True if the flash fired.
+    pub flash: Option<bool>,
+}
+
+impl Exposure {
+    pub fn is_empty(&self) -> bool {
+        *self == Exposure::default()
+    }
+}
+
+impl fmt::Display for Exposure {
+    /// Format like "1/250 s, f/2.8, ISO 200, 50 mm, flash".
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(t) = self.exposure_time {
+            if t > 0. && t < 1. {
+                parts.push(format!("1/{} s", (1. / t).round()));
+            } else {
+                parts.push(format!("{} s", round1(t)));
+            }
+        }
+        if let Some(f) = self.f_number {
+            parts.push(format!("f/{}", round1(f)));
+        }
+        if let Some(iso) = self.iso {
+            parts.push(format!("ISO {}", iso));
+        }
+        if let Some(fl) = self.focal_length {
+            parts.push(format!("{} mm", round1(fl)));
+        }
+        if self.flash == Some(true) {
+            parts.push("flash".into());
+        }
+        out.write_str(&parts.join(", "))
+    }
+}
+
+fn round1(x: f32) -> f32 {
+    (x * 10.).round() / 10.
+}
+
+#[test]
+fn format_exposure() {
+    let exposure = Exposure {
+        exposure_time: Some(0.004),
+        f_number: Some(2.8),
+        iso: Some(200),
+        focal_length: Some(50.),
+        flash: Some(false),
+    };
+    assert_eq!(exposure.to_string(), "1/250 s, f/2.8, ISO 200, 50 mm");
+    let long = Exposure {
+        exposure_time: Some(2.5),
+        flash: Some(true),
+        ..Exposure::default()
+    };
+    assert_eq!(long.to_string(), "2.5 s, flash");
+}
+
 #[derive(Debug, Clone)]
 pub struct Coord {
     pub x: f64,
@@ -392,3 +866,99 @@ impl SizeTag {
         }
     }
 }
+
+/// A format that scaled images are served in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaledFormat {
+    Jpeg,
+    WebP,
+    Avif,
+}
+
+impl ScaledFormat {
+    pub const ALL: [ScaledFormat; 3] =
+        [ScaledFormat::Jpeg, ScaledFormat::WebP, ScaledFormat::Avif];
+
+    pub fn ext(self) -> &'static str {
+        match self {
+            ScaledFormat::Jpeg => "jpg",
+            ScaledFormat::WebP => "webp",
+            ScaledFormat::Avif => "avif",
+        }
+    }
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ScaledFormat::Jpeg => "image/jpeg",
+            ScaledFormat::WebP => "image/webp",
+            ScaledFormat::Avif => "image/avif",
+        }
+    }
+}
+
+impl FromStr for ScaledFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "jpeg" | "jpg" => Ok(ScaledFormat::Jpeg),
+            "webp" => Ok(ScaledFormat::WebP),
+            "avif" => Ok(ScaledFormat::Avif),
+            _ => {
+                Err(format!("Unknown format {:?}, try jpeg, webp or avif", s))
+            }
+        }
+    }
+}
+
+/// An extra size of scaled images, for high or low resolution screens.
+///
+/// The small and medium sizes decide the layout, a variant is an
+/// alternative resolution of the same image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SizeVariant {
+    /// The name in urls, like 4711-s2.jpg.
+    pub name: String,
+    /// Max of width and height in pixels.
+    pub px: u32,
+}
+
+impl FromStr for SizeVariant {
+    type Err = String;
+    /// Parse a variant like "s2=576".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("Bad size variant {:?}, expected like s2=576", s);
+        let pos = s.find('=').ok_or_else(bad)?;
+        let (name, px) = (s[..pos].trim(), s[pos + 1..].trim());
+        if name.is_empty()
+            || !name
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        {
+            return Err(bad());
+        }
+        if ["s", "m", "l"].contains(&name) {
+            return Err(format!("The size name {:?} is reserved", name));
+        }
+        match px.parse() {
+            Ok(px) if px > 0 && px <= SizeTag::Large.px() => Ok(SizeVariant {
+                name: name.into(),
+                px,
+            }),
+            _ => Err(bad()),
+        }
+    }
+}
+
+#[test]
+fn parse_size_variant() {
+    assert_eq!(
+        "s2=576".parse(),
+        Ok(SizeVariant {
+            name: "s2".into(),
+            px: 576,
+        })
+    );
+    assert!("m=720".parse::<SizeVariant>().is_err());
+    assert!("x2".parse::<SizeVariant>().is_err());
+    assert!("x-2=100".parse::<SizeVariant>().is_err());
+    assert!("x=0".parse::<SizeVariant>().is_err());
+}
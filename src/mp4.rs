@@ -0,0 +1,494 @@
+//! Read the metadata I care about from mp4 and quicktime videos.
+//!
+//! The files are made of nested "atoms" (or "boxes"), each with a
+//! size and a four letter type.  The metadata is in the `moov` atom,
+//! which is usually small, even when the file is large.
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use log::debug;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Don't load `moov` atoms larger than this.
+const MAX_MOOV_SIZE: u64 = 64 << 20;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct VideoMeta {
+    /// Creation time, in UTC.
+    pub created: Option<NaiveDateTime>,
+    /// Duration in milliseconds.
+    pub duration: Option<u32>,
+    pub width: u32,
+    pub height: u32,
+    /// Rotation in degrees clockwise, as for photos.
+    pub rotation: i16,
+    pub position: Option<(f64, f64)>,
+}
+
+impl VideoMeta {
+    /// Read metadata from the video file at `path`.
+    ///
+    /// Returns None if the file is not an mp4 or quicktime video.
+    pub fn read_from(path: &Path) -> io::Result<Option<VideoMeta>> {
+        let mut file = File::open(path)?;
+        if file_type(&mut file)?.is_none() {
+            return Ok(None);
+        }
+        let len = file.metadata()?.len();
+        let mut pos = 0;
+        while pos + 8 <= len {
+            file.seek(SeekFrom::Start(pos))?;
+            let (kind, header, size) = read_header(&mut file, len - pos)?;
+            if &kind == b"moov" {
+                if size > MAX_MOOV_SIZE {
+                    debug!("Ignoring huge moov in {:?}", path);
+                    return Ok(None);
+                }
+                let mut moov = vec![0; (size - header) as usize];
+                file.read_exact(&mut moov)?;
+                return Ok(parse_moov(&moov));
+            }
+            pos += size;
+        }
+        Ok(None)
+    }
+}
+
+/// Atoms that may tell where a video was made, or by which camera.
+///
+/// User data (`udta`), metadata (`meta`) and `uuid` atoms may have a
+/// position (like `©xyz`, `loci` or an iso 6709 key), a camera serial
+/// number or xmp data, and so may timed metadata tracks.  None of them
+/// is needed to play the video.  They are hidden by making them
+/// `free` atoms filled with zeroes, so nothing else in the file moves
+/// and range requests still work.
+#[derive(Debug, Default, PartialEq)]
+pub struct PrivateAtoms {
+    /// The start, content start and end of each atom in the file.
+    atoms: Vec<(u64, u64, u64)>,
+}
+
+impl PrivateAtoms {
+    /// Find the private atoms in the video file at `path`.
+    ///
+    /// Fails rather than missing any, if the `moov` atom is too large
+    /// to read.
+    pub fn find(path: &Path) -> io::Result<PrivateAtoms> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut atoms = Vec::new();
+        let mut pos = 0;
+        while pos + 8 <= len {
+            file.seek(SeekFrom::Start(pos))?;
+            let (kind, header, size) = read_header(&mut file, len - pos)?;
+            match &kind {
+                b"udta" | b"meta" | b"uuid" => {
+                    atoms.push((pos, pos + header, pos + size));
+                }
+                b"moov" => {
+                    if size > MAX_MOOV_SIZE {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Huge moov atom",
+                        ));
+                    }
+                    let mut moov = vec![0; (size - header) as usize];
+                    file.read_exact(&mut moov)?;
+                    let at = |p: usize| pos + header + p as u64;
+                    atoms.extend(
+                        private_in_moov(&moov)
+                            .into_iter()
+                            .map(|(s, c, e)| (at(s), at(c), at(e))),
+                    );
+                }
+                _ => (),
+            }
+            pos += size;
+        }
+        Ok(PrivateAtoms { atoms })
+    }
+
+    /// Hide the private atoms in `buf`, read from `pos` in the file.
+    ///
+    /// The size of each atom is kept, the type is changed to `free`
+    /// and the content is zeroed.
+    pub fn hide(&self, pos: u64, buf: &mut [u8]) {
+        let end = pos + buf.len() as u64;
+        for &(start, content, atom_end) in &self.atoms {
+            for at in (start + 4).max(pos)..atom_end.min(end) {
+                let i = (at - pos) as usize;
+                if at < start + 8 {
+                    buf[i] = b"free"[(at - start - 4) as usize];
+                } else if at >= content {
+                    buf[i] = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Find the private atoms in the content of a `moov` atom.
+fn private_in_moov(moov: &[u8]) -> Vec<(usize, usize, usize)> {
+    let mut result = Vec::new();
+    for (kind, start, content, end) in atoms_at(moov) {
+        match &kind {
+            b"udta" | b"meta" | b"uuid" => result.push((start, content, end)),
+            b"trak" => {
+                let trak = &moov[content..end];
+                let handler = find(trak, b"mdia")
+                    .and_then(|mdia| find(mdia, b"hdlr"))
+                    .and_then(|hdlr| hdlr.get(8..12));
+                if handler == Some(b"meta") {
+                    result.push((start, content, end));
+                    continue;
+                }
+                for (kind, s, c, e) in atoms_at(trak) {
+                    if matches!(&kind, b"udta" | b"meta" | b"uuid") {
+                        result.push((content + s, content + c, content + e));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    result
+}
+
+/// Get the format name and mime type of a video file.
+///
+/// Returns None if the file is not an mp4 or quicktime video.
+pub fn file_type(
+    file: &mut File,
+) -> io::Result<Option<(&'static str, &'static str)>> {
+    let mut head = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.take(MAX_FTYP_SIZE).read_to_end(&mut head)?;
+    Ok(type_of(&head))
+}
+
+/// Don't look for brands further than this into the file.
+const MAX_FTYP_SIZE: u64 = 256;
+
+/// Get the format name and mime type from the start of a file.
+///
+/// Heif and avif images are iso media files too, so the brands in
+/// the `ftyp` atom tell if it is a video.
+fn type_of(head: &[u8]) -> Option<(&'static str, &'static str)> {
+    match head.get(4..8)? {
+        b"ftyp" => {
+            let end = u32_at(head, 0)?.min(head.len() as u32) as usize;
+            let major = head.get(8..12)?;
+            let compatible = head.get(16..end).unwrap_or_default();
+            if major == b"qt  " {
+                Some(("mov", "video/quicktime"))
+            } else if is_video_brand(major)
+                || compatible.chunks_exact(4).any(is_video_brand)
+            {
+                Some(("mp4", "video/mp4"))
+            } else {
+                None
+            }
+        }
+        // Old quicktime files may lack the ftyp atom.
+        b"moov" | b"mdat" | b"wide" => Some(("mov", "video/quicktime")),
+        _ => None,
+    }
+}
+
+/// True for brands of mp4 videos, false for heif images and others.
+fn is_video_brand(brand: &[u8]) -> bool {
+    matches!(
+        brand,
+        b"isom" | b"iso2" | b"mp41" | b"mp42" | b"avc1" | b"M4V "
+    ) || brand.starts_with(b"3gp")
+}
+
+/// Read an atom header, giving the type, header size and total size.
+///
+/// The size is limited to `left`, the size left in the file.
+fn read_header(file: &mut File, left: u64) -> io::Result<([u8; 4], u64, u64)> {
+    let mut head = [0; 8];
+    file.read_exact(&mut head)?;
+    let kind = head[4..8].try_into().unwrap();
+    let (header, size) = match u32_at(&head, 0).unwrap() {
+        0 => (8, left),
+        1 => {
+            let mut large = [0; 8];
+            file.read_exact(&mut large)?;
+            (16, u64::from_be_bytes(large))
+        }
+        size => (8, u64::from(size)),
+    };
+    if size < header {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Bad atom size",
+        ));
+    }
+    Ok((kind, header, size.min(left)))
+}
+
+fn parse_moov(moov: &[u8]) -> Option<VideoMeta> {
+    let mut result = VideoMeta::default();
+    for (kind, data) in atoms(moov) {
+        match &kind {
+            b"mvhd" => {
+                parse_mvhd(data, &mut result);
+            }
+            b"trak" if result.width == 0 => {
+                if let Some(tkhd) = find(data, b"tkhd") {
+                    parse_tkhd(tkhd, &mut result);
+                }
+            }
+            b"udta" => {
+                result.position = find(data, b"\xa9xyz").and_then(parse_xyz);
+            }
+            _ => (),
+        }
+    }
+    if result.width > 0 && result.height > 0 {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+/// Get creation time and duration from a movie header.
+fn parse_mvhd(mvhd: &[u8], result: &mut VideoMeta) -> Option<()> {
+    let (created, timescale, duration) = match mvhd.first()? {
+        0 => (
+            u64::from(u32_at(mvhd, 4)?),
+            u32_at(mvhd, 12)?,
+            u64::from(u32_at(mvhd, 16)?),
+        ),
+        _ => (u64_at(mvhd, 4)?, u32_at(mvhd, 20)?, u64_at(mvhd, 24)?),
+    };
+    result.created = mp4_time(created);
+    if timescale > 0 {
+        result.duration =
+            (duration * 1000 / u64::from(timescale)).try_into().ok();
+    }
+    Some(())
+}
+
+/// Get the position from a quicktime `©xyz` user data atom.
+fn parse_xyz(xyz: &[u8]) -> Option<(f64, f64)> {
+    let len = usize::from(u16::from_be_bytes(xyz.get(0..2)?.try_into().ok()?));
+    parse_iso6709(std::str::from_utf8(xyz.get(4..4 + len)?).ok()?)
+}
+
+/// Get dimensions and rotation from a track header, if it is visual.
+fn parse_tkhd(tkhd: &[u8], result: &mut VideoMeta) -> Option<()> {
+    let matrix = if *tkhd.first()? == 0 { 40 } else { 52 };
+    // The values are 16.16 fixed point.
+    let width = u32_at(tkhd, matrix + 36)? >> 16;
+    let height = u32_at(tkhd, matrix + 40)? >> 16;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let (a, b) = (
+        u32_at(tkhd, matrix)? as i32,
+        u32_at(tkhd, matrix + 4)? as i32,
+    );
+    result.rotation = match (a.signum(), b.signum()) {
+        (0, 1) => 90,
+        (-1, 0) => 180,
+        (0, -1) => 270,
+        _ => 0,
+    };
+    result.width = width;
+    result.height = height;
+    Some(())
+}
+
+/// Iterate over the atoms in `data`, giving type and content.
+fn atoms(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    atoms_at(data)
+        .map(move |(kind, _, content, end)| (kind, &data[content..end]))
+}
+
+/// Iterate over the atoms in `data`, giving type and the positions of
+/// the start, the content and the end of each atom.
+fn atoms_at(
+    data: &[u8],
+) -> impl Iterator<Item = ([u8; 4], usize, usize, usize)> + '_ {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let rest = data.get(pos..)?;
+        let kind = rest.get(4..8)?.try_into().ok()?;
+        let (header, size) = match u32_at(rest, 0)? {
+            0 => (8, rest.len()),
+            1 => (16, u64_at(rest, 8)?.try_into().ok()?),
+            size => (8, size.try_into().ok()?),
+        };
+        if header > size || size > rest.len() {
+            return None;
+        }
+        let start = pos;
+        pos += size;
+        Some((kind, start, start + header, pos))
+    })
+}
+
+fn find<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    atoms(data).find(|(k, _)| k == kind).map(|(_, d)| d)
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+/// Convert seconds since 1904, in UTC.  Zero means unknown.
+fn mp4_time(secs: u64) -> Option<NaiveDateTime> {
+    if secs == 0 {
+        return None;
+    }
+    let epoch = NaiveDate::from_ymd_opt(1904, 1, 1)?.and_hms_opt(0, 0, 0)?;
+    Some(epoch + Duration::seconds(secs.try_into().ok()?))
+}
+
+/// Parse a position like "+59.3293+018.0686+012.345/".
+fn parse_iso6709(s: &str) -> Option<(f64, f64)> {
+    let s = s.split('/').next()?;
+    let sign = |c| c == '+' || c == '-';
+    let i = s.get(1..)?.find(sign)? + 1;
+    let j = s[i + 1..].find(sign).map_or(s.len(), |j| j + i + 1);
+    Some((s[..i].parse().ok()?, s[i..j].parse().ok()?))
+}
+
+#[test]
+fn ftyp_brands() {
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + 4 * compatible.len() as u32;
+        let mut head = size.to_be_bytes().to_vec();
+        head.extend_from_slice(b"ftyp");
+        head.extend_from_slice(major);
+        head.extend_from_slice(&[0; 4]);
+        compatible.iter().for_each(|b| head.extend_from_slice(*b));
+        head.extend_from_slice(b"\0\0\0\x08free");
+        head
+    }
+    let mp4 = Some(("mp4", "video/mp4"));
+    assert_eq!(type_of(&ftyp(b"qt  ", &[b"qt  "])).unwrap().0, "mov");
+    assert_eq!(type_of(&ftyp(b"isom", &[b"isom", b"avc1"])), mp4);
+    assert_eq!(type_of(&ftyp(b"3gp5", &[])), mp4);
+    assert_eq!(type_of(&ftyp(b"XAVC", &[b"mp42", b"iso2"])), mp4);
+    assert_eq!(type_of(&ftyp(b"heic", &[b"mif1", b"heic"])), None);
+    assert_eq!(type_of(&ftyp(b"mif1", &[b"mif1", b"heix"])), None);
+    assert_eq!(type_of(&ftyp(b"avif", &[b"avif", b"mif1", b"miaf"])), None);
+    assert_eq!(type_of(b"\0\0"), None);
+}
+
+#[test]
+fn iso6709_with_altitude() {
+    assert_eq!(
+        parse_iso6709("+59.3293+018.0686+012.345/"),
+        Some((59.3293, 18.0686)),
+    );
+}
+
+#[test]
+fn iso6709_negative() {
+    assert_eq!(
+        parse_iso6709("-33.8688-070.6693/"),
+        Some((-33.8688, -70.6693))
+    );
+}
+
+#[cfg(test)]
+pub fn atom(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut a = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+    a.extend_from_slice(kind);
+    a.extend_from_slice(content);
+    a
+}
+
+#[test]
+fn parse_minimal_moov() {
+    let mut mvhd = vec![0; 100];
+    // 2021-05-16 12:00:00 UTC, in seconds since 1904.
+    mvhd[4..8].copy_from_slice(&3704011200u32.to_be_bytes());
+    mvhd[12..16].copy_from_slice(&600u32.to_be_bytes());
+    mvhd[16..20].copy_from_slice(&3000u32.to_be_bytes());
+    let mut tkhd = vec![0; 84];
+    tkhd[44..48].copy_from_slice(&0x10000u32.to_be_bytes());
+    tkhd[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+    tkhd[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+    let xyz = b"\0\x12\0\0+59.3293+018.0686/";
+    let moov = [
+        atom(b"mvhd", &mvhd),
+        atom(b"trak", &atom(b"tkhd", &tkhd)),
+        atom(b"udta", &atom(b"\xa9xyz", xyz)),
+    ]
+    .concat();
+    assert_eq!(
+        parse_moov(&moov),
+        Some(VideoMeta {
+            created: NaiveDate::from_ymd_opt(2021, 5, 16)
+                .and_then(|d| d.and_hms_opt(12, 0, 0)),
+            duration: Some(5000),
+            width: 1920,
+            height: 1080,
+            rotation: 90,
+            position: Some((59.3293, 18.0686)),
+        }),
+    );
+}
+
+#[test]
+fn hide_private_atoms() {
+    let mut tkhd = vec![0; 84];
+    tkhd[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+    tkhd[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+    let mut hdlr = vec![0; 24];
+    hdlr[8..12].copy_from_slice(b"meta");
+    let xyz = b"\0\x12\0\0+59.3293+018.0686/";
+    let moov = [
+        atom(b"mvhd", &[0; 100]),
+        atom(
+            b"trak",
+            &[atom(b"tkhd", &tkhd), atom(b"udta", b"serial 1234")].concat(),
+        ),
+        atom(b"trak", &atom(b"mdia", &atom(b"hdlr", &hdlr))),
+        atom(b"udta", &atom(b"\xa9xyz", xyz)),
+        atom(b"meta", b"+59.3293+018.0686/"),
+    ]
+    .concat();
+    let data = [
+        atom(b"ftyp", b"isom\0\0\0\0isom"),
+        atom(b"moov", &moov),
+        atom(b"uuid", b"xmp at 59.3293"),
+        atom(b"mdat", b"frames"),
+    ]
+    .concat();
+    let path = std::env::temp_dir()
+        .join(format!("rphotos-mp4-test-{}.mp4", std::process::id()));
+    std::fs::write(&path, &data).unwrap();
+    let private = PrivateAtoms::find(&path);
+    std::fs::remove_file(&path).unwrap();
+    let private = private.unwrap();
+    assert_eq!(private.atoms.len(), 5);
+
+    let mut hidden = data.clone();
+    for (i, chunk) in hidden.chunks_mut(7).enumerate() {
+        private.hide(i as u64 * 7, chunk);
+    }
+    assert_eq!(hidden.len(), data.len());
+    let text = String::from_utf8_lossy(&hidden);
+    assert!(!text.contains("59.3293"));
+    assert!(!text.contains("serial"));
+    assert!(!text.contains("udta") && !text.contains("uuid"));
+    assert!(text.ends_with("mdatframes"));
+    let moov = find(&hidden, b"moov").unwrap();
+    assert_eq!(
+        atoms(moov).map(|(kind, _)| kind).collect::<Vec<_>>(),
+        [*b"mvhd", *b"trak", *b"free", *b"free", *b"free"],
+    );
+    let meta = parse_moov(moov).unwrap();
+    assert_eq!((meta.width, meta.position), (1920, None));
+}
@@ -1,6 +1,8 @@
 //! Extract all the exif data I care about
 use crate::adm::result::Error;
-use chrono::{Date, Local, NaiveDate, NaiveDateTime, Utc};
+use crate::models::Exposure;
+use crate::mp4::VideoMeta;
+use chrono::{Date, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use exif::{Field, In, Reader, Tag, Value};
 use log::{debug, error, warn};
 use std::fs::File;
@@ -22,9 +24,44 @@ pub struct ExifData {
     longval: Option<f64>,
     latref: Option<String>,
     longref: Option<String>,
+    /// Offset of `dateval` from UTC, in seconds.
+    offset: Option<i32>,
+    exposure_time: Option<f64>,
+    f_number: Option<f64>,
+    iso: Option<u32>,
+    focal_length: Option<f64>,
+    flash: Option<u32>,
+    lens_make: Option<String>,
+    lens_model: Option<String>,
+    artist: Option<String>,
+    copyright: Option<String>,
 }
 
 impl ExifData {
+    /// The metadata of a video, in the same form as for a photo.
+    ///
+    /// A video has its creation time in UTC, but no timezone, so it
+    /// is converted to the timezone of this host and the offset is
+    /// unknown.
+    pub fn for_video(video: &VideoMeta) -> Self {
+        ExifData {
+            dateval: video
+                .created
+                .map(|utc| Local.from_utc_datetime(&utc).naive_local()),
+            width: Some(video.width),
+            height: Some(video.height),
+            orientation: match video.rotation {
+                90 => Some(6),
+                180 => Some(3),
+                270 => Some(8),
+                _ => None,
+            },
+            latval: video.position.map(|(lat, _)| lat),
+            longval: video.position.map(|(_, long)| long),
+            ..Default::default()
+        }
+    }
+
     pub fn read_from(path: &Path) -> Result<Self, Error> {
         let mut result = Self::default();
         let file = File::open(path).map_err(|e| Error::in_file(&e, path))?;
@@ -70,6 +107,32 @@ impl ExifData {
                 } else if let Some(s) = is_string(f, Tag::GPSImgDirection) {
                     println!("  direction: {}", s);
                 */
+                } else if let Some(o) = is_offset(f, Tag::OffsetTimeOriginal) {
+                    result.offset = Some(o);
+                } else if let Some(o) = is_offset(f, Tag::OffsetTime) {
+                    if result.offset.is_none() {
+                        result.offset = Some(o);
+                    }
+                } else if let Some(t) = is_rational(f, Tag::ExposureTime) {
+                    result.exposure_time = Some(t);
+                } else if let Some(n) = is_rational(f, Tag::FNumber) {
+                    result.f_number = Some(n);
+                } else if let Some(l) = is_rational(f, Tag::FocalLength) {
+                    result.focal_length = Some(l);
+                } else if let Some(iso) =
+                    is_u32(f, Tag::PhotographicSensitivity)
+                {
+                    result.iso = Some(iso);
+                } else if let Some(flash) = is_u32(f, Tag::Flash) {
+                    result.flash = Some(flash);
+                } else if let Some(s) = is_string(f, Tag::LensMake) {
+                    result.lens_make = Some(s.to_string());
+                } else if let Some(s) = is_string(f, Tag::LensModel) {
+                    result.lens_model = Some(s.to_string());
+                } else if let Some(s) = is_string(f, Tag::Artist) {
+                    result.artist = Some(s.to_string());
+                } else if let Some(s) = is_copyright(f) {
+                    result.copyright = Some(s.to_string());
                 } else if let Some(d) = is_date(f, Tag::GPSDateStamp) {
                     result.gpsdate = Some(d);
                 } else if let Some(hms) = is_time(f, Tag::GPSTimeStamp) {
@@ -81,22 +144,67 @@ impl ExifData {
         Ok(result)
     }
 
+    /// The local time where the photo was taken.
     pub fn date(&self) -> Option<NaiveDateTime> {
-        // Note: I probably return and store datetime with tz,
-        // possibly utc, instead.
-        if let (&Some(date), &Some((h, m, s))) = (&self.gpsdate, &self.gpstime)
-        {
-            let naive = date
-                .and_hms(u32::from(h), u32::from(m), u32::from(s))
-                .with_timezone(&Local)
-                .naive_local();
-            debug!("GPS Date {}, {}:{}:{} => {}", date, h, m, s, naive);
-            Some(naive)
-        } else if let Some(date) = self.dateval {
-            Some(date)
-        } else {
+        let date = self.local_time().map(|(date, _, _)| date);
+        if date.is_none() {
             warn!("No date found in exif");
-            None
+        }
+        date
+    }
+
+    /// The offset of [`ExifData::date`] from UTC in seconds, if known.
+    pub fn utc_offset(&self) -> Option<i32> {
+        self.local_time().and_then(|(_, offset, _)| offset)
+    }
+
+    /// True if [`ExifData::date`] is from the GPS rather than from
+    /// the camera clock, so it needs no correction.
+    pub fn date_is_from_gps(&self) -> bool {
+        self.local_time().is_some_and(|(_, _, gps)| gps)
+    }
+
+    /// Get the local time, its offset from UTC, and if it is from GPS.
+    ///
+    /// The GPS time is preferred, since camera clocks drift.  The
+    /// offset is given by the camera, or by the difference between
+    /// the GPS time and the camera time.  If there is only a GPS
+    /// time, it is converted to the timezone of this host, but the
+    /// offset is unknown, since the host may be anywhere.
+    fn local_time(&self) -> Option<(NaiveDateTime, Option<i32>, bool)> {
+        let gps = match (&self.gpsdate, &self.gpstime) {
+            (Some(date), Some((h, m, s))) => Some(
+                date.and_hms(u32::from(*h), u32::from(*m), u32::from(*s))
+                    .naive_utc(),
+            ),
+            _ => None,
+        };
+        match (gps, self.dateval, self.offset) {
+            (Some(utc), _, Some(offset)) => Some((
+                utc + Duration::seconds(offset.into()),
+                Some(offset),
+                true,
+            )),
+            (Some(utc), Some(local), None) => {
+                match offset_between(utc, local) {
+                    Some(offset) => Some((
+                        utc + Duration::seconds(offset.into()),
+                        Some(offset),
+                        true,
+                    )),
+                    None => {
+                        warn!("Camera time {} is far from GPS {}", local, utc);
+                        Some((local, None, false))
+                    }
+                }
+            }
+            (Some(utc), None, None) => {
+                let local = Local.from_utc_datetime(&utc);
+                debug!("GPS Date {} => {}", utc, local);
+                Some((local.naive_local(), None, true))
+            }
+            (None, Some(local), offset) => Some((local, offset, false)),
+            (None, None, _) => None,
         }
     }
     pub fn camera(&self) -> Option<(&str, &str)> {
@@ -107,6 +215,40 @@ impl ExifData {
             None
         }
     }
+    /// The lens make and model.
+    ///
+    /// Many cameras only give the lens model, then the camera make
+    /// is used, if known.
+    pub fn lens(&self) -> Option<(&str, &str)> {
+        let model = self.lens_model.as_deref()?.trim();
+        if model.is_empty() {
+            return None;
+        }
+        let make = self
+            .lens_make
+            .as_deref()
+            .or(self.make.as_deref())
+            .unwrap_or("");
+        Some((make.trim(), model))
+    }
+    /// Who to attribute the photo to, from the artist or copyright.
+    pub fn attribution(&self) -> Option<&str> {
+        self.artist
+            .as_deref()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .or_else(|| self.copyright.as_deref().and_then(copyright_holder))
+    }
+    pub fn exposure(&self) -> Exposure {
+        Exposure {
+            exposure_time: self.exposure_time.map(|t| t as f32),
+            f_number: self.f_number.map(|n| n as f32),
+            iso: self.iso.map(|iso| iso as i32),
+            focal_length: self.focal_length.map(|l| l as f32),
+            // Bit 0 of the flash value tells if the flash fired.
+            flash: self.flash.map(|flash| flash & 1 == 1),
+        }
+    }
     pub fn position(&self) -> Option<(f64, f64)> {
         if let (Some(lat), Some(long)) = (self.lat(), self.long()) {
             Some((lat, long))
@@ -137,23 +279,65 @@ impl ExifData {
         }
     }
 
-    pub fn rotation(&self) -> Result<i16, Error> {
+    /// Get the rotation in degrees clockwise and if the image should
+    /// be mirrored.  The mirroring is done before the rotation.
+    pub fn orientation(&self) -> Result<(i16, bool), Error> {
         if let Some(value) = self.orientation {
             debug!("Raw orientation is {}", value);
             match value {
-                1 | 0 => Ok(0),
-                3 => Ok(180),
-                6 => Ok(90),
-                8 => Ok(270),
+                1 | 0 => Ok((0, false)),
+                2 => Ok((0, true)),
+                3 => Ok((180, false)),
+                4 => Ok((180, true)),
+                5 => Ok((270, true)),
+                6 => Ok((90, false)),
+                7 => Ok((90, true)),
+                8 => Ok((270, false)),
                 x => Err(Error::UnknownOrientation(x)),
             }
         } else {
             debug!("Orientation tag missing, default to 0 degrees");
-            Ok(0)
+            Ok((0, false))
         }
     }
 }
 
+/// Get the timezone offset from UTC time `utc` to local time `local`.
+///
+/// The offset is rounded to whole quarters of an hour, as any
+/// remaining difference is the camera clock being off.  None if the
+/// difference is too large to be a timezone.
+fn offset_between(utc: NaiveDateTime, local: NaiveDateTime) -> Option<i32> {
+    let quarters = ((local - utc).num_seconds() as f64 / 900.).round();
+    if quarters.abs() <= 14. * 4. {
+        Some(quarters as i32 * 900)
+    } else {
+        None
+    }
+}
+
+/// Parse an exif offset time, like "+02:00".
+fn is_offset(f: &Field, tag: Tag) -> Option<i32> {
+    if f.tag == tag {
+        single_ascii(&f.value)
+            .ok()
+            .and_then(|s| parse_offset(s.trim()))
+    } else {
+        None
+    }
+}
+
+fn parse_offset(s: &str) -> Option<i32> {
+    let sign = match s.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let (h, m) = s.get(1..)?.split_at(s[1..].find(':')?);
+    let m = m[1..].parse::<i32>().ok().filter(|m| (0..60).contains(m))?;
+    Some(sign * (h.parse::<i32>().ok()? * 3600 + m * 60))
+}
+
 fn is_lat_long(f: &Field, tag: Tag) -> Option<f64> {
     if f.tag == tag {
         match f.value {
@@ -171,6 +355,62 @@ fn is_lat_long(f: &Field, tag: Tag) -> Option<f64> {
     }
 }
 
+/// The exif copyright is the photographer and the editor copyright,
+/// separated by a null.  Only the photographer is of interest.
+fn is_copyright(f: &Field) -> Option<&str> {
+    if f.tag == Tag::Copyright {
+        match &f.value {
+            Value::Ascii(v) => v.first().and_then(|s| from_utf8(s).ok()),
+            v => {
+                error!("Expected string for copyright: {:?}", v);
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
+/// Get the name from a copyright notice like "(c) 2021 Kim Doe".
+fn copyright_holder(notice: &str) -> Option<&str> {
+    let mut s = notice.trim();
+    loop {
+        let lower = s.to_lowercase();
+        let skip = ["copyright", "(c)", "©", ":"]
+            .iter()
+            .find(|p| lower.starts_with(*p))
+            .map(|p| p.len())
+            .or_else(|| {
+                let years = s
+                    .find(|c: char| !(c.is_ascii_digit() || c == '-'))
+                    .unwrap_or(s.len());
+                Some(years).filter(|n| *n > 0)
+            });
+        match skip {
+            Some(n) => s = s[n..].trim_start(),
+            None => break,
+        }
+    }
+    Some(s.trim_end_matches(|c: char| c == '.' || c.is_whitespace()))
+        .filter(|s| !s.is_empty())
+}
+
+fn is_rational(f: &Field, tag: Tag) -> Option<f64> {
+    if f.tag == tag {
+        match &f.value {
+            Value::Rational(v) if v.len() == 1 && v[0].denom != 0 => {
+                Some(v[0].to_f64())
+            }
+            v => {
+                error!("Expected rational for {}: {:?}", tag, v);
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
 fn is_datetime(f: &Field, tag: Tag) -> Option<NaiveDateTime> {
     if f.tag == tag {
         single_ascii(&f.value)
@@ -271,3 +511,102 @@ fn single_ascii(value: &Value) -> Result<&str, Error> {
         ))),
     }
 }
+
+#[test]
+fn copyright_notices() {
+    assert_eq!(copyright_holder("Kim Doe"), Some("Kim Doe"));
+    assert_eq!(copyright_holder("© 2021 Kim Doe"), Some("Kim Doe"));
+    assert_eq!(
+        copyright_holder("Copyright (C) 2019-2021 Kim Doe."),
+        Some("Kim Doe")
+    );
+    assert_eq!(copyright_holder("Copyright: 2020"), None);
+    assert_eq!(copyright_holder("  "), None);
+}
+
+#[test]
+fn mirrored_orientations() {
+    let orientation = |o| {
+        ExifData {
+            orientation: Some(o),
+            ..ExifData::default()
+        }
+        .orientation()
+        .ok()
+    };
+    assert_eq!(orientation(1), Some((0, false)));
+    assert_eq!(orientation(2), Some((0, true)));
+    assert_eq!(orientation(5), Some((270, true)));
+    assert_eq!(orientation(7), Some((90, true)));
+    assert_eq!(orientation(9), None);
+}
+
+#[test]
+fn exif_offsets() {
+    assert_eq!(parse_offset("+02:00"), Some(7200));
+    assert_eq!(parse_offset("-05:30"), Some(-19800));
+    assert_eq!(parse_offset("   :  "), None);
+    assert_eq!(parse_offset("+01:75"), None);
+}
+
+#[test]
+fn offset_from_gps() {
+    let utc = NaiveDate::from_ymd_opt(2021, 5, 22)
+        .and_then(|d| d.and_hms_opt(10, 0, 0))
+        .unwrap();
+    // A camera clock two minutes behind, in UTC+2.
+    let local = utc + Duration::seconds(2 * 3600 - 120);
+    assert_eq!(offset_between(utc, local), Some(7200));
+    assert_eq!(offset_between(utc, utc + Duration::days(2)), None);
+}
+
+#[test]
+fn no_offset_from_host() {
+    let utc = NaiveDate::from_ymd_opt(2021, 5, 22)
+        .and_then(|d| d.and_hms_opt(10, 0, 0))
+        .unwrap();
+    let gps_only = ExifData {
+        gpsdate: Some(Date::from_utc(utc.date(), Utc)),
+        gpstime: Some((10, 0, 0)),
+        ..Default::default()
+    };
+    assert!(gps_only.date().is_some());
+    assert_eq!(gps_only.utc_offset(), None);
+    let video = ExifData::for_video(&VideoMeta {
+        created: Some(utc),
+        ..Default::default()
+    });
+    assert!(video.date().is_some());
+    assert_eq!(video.utc_offset(), None);
+}
+
+#[test]
+fn date_from_gps_or_camera() {
+    let gps = ExifData {
+        gpsdate: NaiveDate::from_ymd_opt(2021, 5, 22)
+            .map(|d| Date::from_utc(d, Utc)),
+        gpstime: Some((10, 0, 0)),
+        ..Default::default()
+    };
+    let camera_time = |h, m| {
+        NaiveDate::from_ymd_opt(2021, 5, 22)
+            .and_then(|d| d.and_hms_opt(h, m, 0))
+    };
+    assert!(gps.date_is_from_gps());
+    let camera = ExifData {
+        dateval: camera_time(12, 2),
+        ..Default::default()
+    };
+    assert!(!camera.date_is_from_gps());
+    let both = ExifData {
+        dateval: camera_time(12, 2),
+        ..gps
+    };
+    assert!(both.date_is_from_gps());
+    assert_eq!(both.date(), camera_time(12, 0));
+    let far_off = ExifData {
+        dateval: camera_time(23, 50).map(|d| d + Duration::days(3)),
+        ..both
+    };
+    assert!(!far_off.date_is_from_gps());
+}
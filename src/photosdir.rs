@@ -1,10 +1,18 @@
-use crate::models::Photo;
+use crate::models::{Photo, ScaledFormat};
+use crate::mp4::{self, VideoMeta};
 use crate::myexif::ExifData;
+use crate::watermark::Mark;
+use crate::xmp::{embed_in_jpeg, embed_in_webp, sidecar_path, XmpData};
+use chrono::{DateTime, NaiveDateTime, Timelike, Utc};
 use image::imageops::FilterType;
-use image::{self, GenericImageView, ImageError, ImageFormat};
+use image::{self, DynamicImage, GenericImageView, ImageError, ImageFormat};
 use log::{debug, info, warn};
+use rayon::{Scope, ThreadPoolBuilder};
+use sha2::{Digest, Sha256};
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
 use std::{fs, io};
 use tokio::task::{spawn_blocking, JoinError};
 
@@ -27,26 +35,115 @@ impl PhotosDir {
         self.basedir.join(Path::new(path)).is_file()
     }
 
-    pub fn find_files(
+    /// Find all files in `dir`, recursively, using `jobs` threads.
+    ///
+    /// Directories are read and `load` is called for each file in
+    /// the worker threads.  The results from `load` are passed to
+    /// `handle` in the calling thread, in batches of at most `batch`
+    /// items, as soon as they are available.
+    ///
+    /// If `jobs` is zero, rayon picks a number of threads based on
+    /// the number of CPUs.
+    pub fn find_files<T: Send>(
         &self,
         dir: &Path,
-        cb: &dyn Fn(&str, &ExifData),
+        jobs: usize,
+        batch: usize,
+        load: &(dyn Fn(&FoundFile) -> Option<T> + Sync),
+        handle: &mut dyn FnMut(Vec<T>),
     ) -> io::Result<()> {
         let absdir = self.basedir.join(dir);
-        if fs::metadata(&absdir)?.is_dir() {
-            debug!("Should look in {:?}", absdir);
-            for entry in fs::read_dir(absdir)? {
+        if !fs::metadata(&absdir)?.is_dir() {
+            return Ok(());
+        }
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .map_err(io::Error::other)?;
+        let failure = Mutex::new(None);
+        let (tx, rx) = channel();
+        pool.in_place_scope(|scope| {
+            let failure = &failure;
+            scope.spawn(move |s| self.walk(s, absdir, load, tx, failure));
+            while let Ok(first) = rx.recv() {
+                let mut items = vec![first];
+                items.extend(rx.try_iter().take(batch.max(1) - 1));
+                handle(items);
+            }
+        });
+        match failure.into_inner() {
+            Ok(Some(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read one directory, spawning jobs for its content.
+    ///
+    /// The first error encountered is stored in `failure`, and stops
+    /// further traversal.  Jobs that are already spawned when it
+    /// happens return without loading or sending anything.
+    fn walk<'s, T: Send>(
+        &'s self,
+        scope: &Scope<'s>,
+        absdir: PathBuf,
+        load: &'s (dyn Fn(&FoundFile) -> Option<T> + Sync),
+        tx: Sender<T>,
+        failure: &'s Mutex<Option<io::Error>>,
+    ) {
+        let failed = move || failure.lock().map_or(true, |f| f.is_some());
+        if failed() {
+            return;
+        }
+        debug!("Should look in {:?}", absdir);
+        let result = fs::read_dir(absdir).and_then(|entries| {
+            for entry in entries {
+                if failed() {
+                    break;
+                }
                 let path = entry?.path();
-                if fs::metadata(&path)?.is_dir() {
-                    self.find_files(&path, cb)?;
-                } else if let Some(exif) = load_meta(&path) {
-                    cb(self.subpath(&path)?, &exif);
+                let meta = fs::metadata(&path)?;
+                let tx = tx.clone();
+                if meta.is_dir() {
+                    scope
+                        .spawn(move |s| self.walk(s, path, load, tx, failure));
                 } else {
-                    debug!("{:?} is no pic.", path)
+                    let file = self.found_file(path, &meta)?;
+                    scope.spawn(move |_| {
+                        if failed() {
+                            return;
+                        }
+                        if let Some(item) = load(&file) {
+                            // The receiver is gone only if the main
+                            // thread has panicked.
+                            let _ = tx.send(item);
+                        }
+                    });
                 }
             }
+            Ok(())
+        });
+        if let Err(e) = result {
+            if let Ok(mut failure) = failure.lock() {
+                failure.get_or_insert(e);
+            }
         }
-        Ok(())
+    }
+
+    /// Get a [`FoundFile`] for a single file, given its full path.
+    pub fn get_file(&self, fullpath: &Path) -> io::Result<FoundFile> {
+        self.found_file(fullpath.into(), &fs::metadata(fullpath)?)
+    }
+
+    fn found_file(
+        &self,
+        fullpath: PathBuf,
+        meta: &fs::Metadata,
+    ) -> io::Result<FoundFile> {
+        Ok(FoundFile {
+            path: self.subpath(&fullpath)?.to_string(),
+            fingerprint: Fingerprint::of(meta)?,
+            fullpath,
+        })
     }
 
     fn subpath<'a>(&self, fullpath: &'a Path) -> Result<&'a str, io::Error> {
@@ -62,6 +159,90 @@ impl PhotosDir {
     }
 }
 
+/// A file found by [`PhotosDir::find_files`] or [`PhotosDir::get_file`].
+pub struct FoundFile {
+    /// The path relative to the image root.
+    pub path: String,
+    pub fingerprint: Fingerprint,
+    fullpath: PathBuf,
+}
+
+impl FoundFile {
+    /// Read metadata from the file, or None if it is not an image.
+    pub fn load_meta(&self) -> Option<ExifData> {
+        load_meta(&self.fullpath)
+    }
+
+    /// Read metadata from the file, if it is a video.
+    pub fn load_video(&self) -> io::Result<Option<VideoMeta>> {
+        VideoMeta::read_from(&self.fullpath)
+    }
+
+    /// Read xmp data from sidecar or embedded in the file.
+    ///
+    /// Failure to read xmp is logged, but otherwise ignored.
+    pub fn load_xmp(&self) -> Option<XmpData> {
+        XmpData::read_for(&self.fullpath).unwrap_or_else(|e| {
+            warn!("Failed to read xmp for {}: {}", self.path, e);
+            None
+        })
+    }
+
+    /// The fingerprint of the xmp sidecar of the file, if any.
+    ///
+    /// The inode is not included, since editors tend to replace the
+    /// sidecar rather than write to it.
+    pub fn sidecar_fingerprint(&self) -> Option<Fingerprint> {
+        let meta = fs::metadata(sidecar_path(&self.fullpath)?).ok()?;
+        let fingerprint = Fingerprint::of(&meta).ok()?;
+        Some(Fingerprint {
+            inode: None,
+            ..fingerprint
+        })
+    }
+
+    /// Perceptual hash of the image, see [`dhash`].
+    pub fn perceptual_hash(&self) -> Result<u64, ImageLoadFailed> {
+        dhash(&self.fullpath)
+    }
+
+    /// The format of the file, see [`FileFormat::of`].
+    pub fn file_format(&self) -> io::Result<Option<FileFormat>> {
+        FileFormat::of(&self.fullpath)
+    }
+
+    /// Hex-encoded sha256 of the file contents.
+    pub fn content_hash(&self) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(&self.fullpath)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+/// Enough data about a file to tell if it has changed.
+///
+/// The modification time is truncated to microseconds, since that is
+/// what the database stores.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub size: i64,
+    pub mtime: NaiveDateTime,
+    pub inode: Option<i64>,
+}
+
+impl Fingerprint {
+    fn of(meta: &fs::Metadata) -> io::Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let mtime = DateTime::<Utc>::from(meta.modified()?).naive_utc();
+        let micros = mtime.nanosecond() / 1000 * 1000;
+        Ok(Fingerprint {
+            size: meta.len() as i64,
+            mtime: mtime.with_nanosecond(micros).unwrap_or(mtime),
+            inode: Some(meta.ino() as i64),
+        })
+    }
+}
+
 fn load_meta(path: &Path) -> Option<ExifData> {
     if let Ok(mut exif) = ExifData::read_from(&path) {
         if exif.width.is_none() || exif.height.is_none() {
@@ -90,6 +271,7 @@ fn actual_image_size(path: &Path) -> Result<(u32, u32), ImageError> {
 pub enum ImageLoadFailed {
     File(io::Error),
     Image(image::ImageError),
+    Avif(ravif::Error),
     Join(JoinError),
 }
 
@@ -100,6 +282,7 @@ impl std::fmt::Display for ImageLoadFailed {
         match &self {
             ImageLoadFailed::File(e) => e.fmt(out),
             ImageLoadFailed::Image(e) => e.fmt(out),
+            ImageLoadFailed::Avif(e) => e.fmt(out),
             ImageLoadFailed::Join(e) => e.fmt(out),
         }
     }
@@ -115,30 +298,55 @@ impl From<image::ImageError> for ImageLoadFailed {
         ImageLoadFailed::Image(e)
     }
 }
+impl From<ravif::Error> for ImageLoadFailed {
+    fn from(e: ravif::Error) -> ImageLoadFailed {
+        ImageLoadFailed::Avif(e)
+    }
+}
 impl From<JoinError> for ImageLoadFailed {
     fn from(e: JoinError) -> ImageLoadFailed {
         ImageLoadFailed::Join(e)
     }
 }
 
-pub async fn get_scaled_jpeg(
+/// Quality of scaled webp images.
+///
+/// This gives about the same quality as the default of jpeg, in
+/// fewer bytes.
+const WEBP_QUALITY: f32 = 80.;
+
+/// Quality of scaled avif images, about the same as for webp.
+const AVIF_QUALITY: f32 = 70.;
+
+/// Speed of the avif encoder, 1 (slow) to 10 (fast).
+///
+/// Images are encoded on demand, so this is fast rather than small.
+const AVIF_SPEED: u8 = 8;
+
+/// Version of the scaling and encoding of images.
+///
+/// Increase this when a change here changes the scaled images, so
+/// browsers get the new images.  Clear the cache too.
+pub const SCALER_VERSION: u32 = 2;
+
+/// Get the image at `path` scaled to `size`, in `format`.
+///
+/// The `mark`, if any, is drawn on the image and the `xmp` packet, if
+/// any, is embedded in the result (except for avif, which the
+/// encoder has no support for).
+pub async fn get_scaled_image(
     path: PathBuf,
     rotation: i16,
+    flip: bool,
     size: u32,
+    format: ScaledFormat,
+    xmp: Option<String>,
+    mark: Option<Mark>,
 ) -> Result<Vec<u8>, ImageLoadFailed> {
     spawn_blocking(move || {
         info!("Should open {:?}", path);
 
-        let img = if is_jpeg(&path) {
-            use std::fs::File;
-            use std::io::BufReader;
-            let file = BufReader::new(File::open(path)?);
-            let mut decoder = image::jpeg::JpegDecoder::new(file)?;
-            decoder.scale(size as u16, size as u16)?;
-            image::DynamicImage::from_decoder(decoder)?
-        } else {
-            image::open(path)?
-        };
+        let img = open_scaled(&path, size)?;
 
         let img = if 3 * size <= img.width() || 3 * size <= img.height() {
             info!("T-nail from {}x{} to {}", img.width(), img.height(), size);
@@ -149,6 +357,7 @@ pub async fn get_scaled_jpeg(
         } else {
             img
         };
+        let img = if flip { img.fliph() } else { img };
         let img = match rotation {
             _x @ 0..=44 | _x @ 315..=360 => img,
             _x @ 45..=134 => img.rotate90(),
@@ -159,13 +368,92 @@ pub async fn get_scaled_jpeg(
                 img
             }
         };
-        let mut buf = Vec::new();
-        img.write_to(&mut buf, ImageFormat::Jpeg)?;
-        Ok(buf)
+        let img = match mark {
+            Some(mark) => {
+                let mut img = img.to_rgb8();
+                mark.apply(&mut img);
+                DynamicImage::ImageRgb8(img)
+            }
+            None => img,
+        };
+        Ok(match format {
+            ScaledFormat::Jpeg => {
+                let mut buf = Vec::new();
+                img.write_to(&mut buf, ImageFormat::Jpeg)?;
+                match xmp {
+                    Some(xmp) => embed_in_jpeg(buf, &xmp),
+                    None => buf,
+                }
+            }
+            ScaledFormat::WebP => {
+                let img = img.to_rgb8();
+                let (w, h) = img.dimensions();
+                let data = webp::Encoder::from_rgb(&img, w, h)
+                    .encode(WEBP_QUALITY)
+                    .to_vec();
+                match xmp {
+                    Some(xmp) => embed_in_webp(data, w, h, &xmp),
+                    None => data,
+                }
+            }
+            ScaledFormat::Avif => {
+                let img = img.to_rgb8();
+                let (w, h) = img.dimensions();
+                let pixels = img
+                    .pixels()
+                    .map(|p| ravif::RGB8::new(p[0], p[1], p[2]))
+                    .collect::<Vec<_>>();
+                ravif::Encoder::new()
+                    .with_quality(AVIF_QUALITY)
+                    .with_speed(AVIF_SPEED)
+                    .encode_rgb(ravif::Img::new(&pixels, w as _, h as _))?
+                    .avif_file
+            }
+        })
     })
     .await?
 }
 
+/// Open an image, at least `size` pixels in each direction if possible.
+///
+/// A jpeg image is scaled while decoding, which is a lot faster than
+/// decoding it in full size.
+fn open_scaled(
+    path: &Path,
+    size: u32,
+) -> Result<DynamicImage, ImageLoadFailed> {
+    Ok(if is_jpeg(path) {
+        use std::io::BufReader;
+        let file = BufReader::new(fs::File::open(path)?);
+        let mut decoder = image::jpeg::JpegDecoder::new(file)?;
+        decoder.scale(size as u16, size as u16)?;
+        DynamicImage::from_decoder(decoder)?
+    } else {
+        image::open(path)?
+    })
+}
+
+/// Calculate a perceptual hash (dHash) of an image.
+///
+/// The image is reduced to 9x8 gray pixels, and each bit in the hash
+/// tells if a pixel is darker than its right neighbour.  Similar
+/// images get hashes that differ only in a few bits.
+pub fn dhash(path: &Path) -> Result<u64, ImageLoadFailed> {
+    let img = open_scaled(path, 64)?
+        .resize_exact(9, 8, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if img.get_pixel(x, y)[0] < img.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Ok(hash)
+}
+
 fn is_jpeg(path: &Path) -> bool {
     if let Some(suffix) = path.extension().and_then(|s| s.to_str()) {
         suffix.eq_ignore_ascii_case("jpg")
@@ -174,3 +462,135 @@ fn is_jpeg(path: &Path) -> bool {
         false
     }
 }
+
+/// File name extensions and mime types of camera raw formats.
+const RAW_FORMATS: [(&str, &str); 8] = [
+    ("arw", "image/x-sony-arw"),
+    ("cr2", "image/x-canon-cr2"),
+    ("cr3", "image/x-canon-cr3"),
+    ("dng", "image/x-adobe-dng"),
+    ("nef", "image/x-nikon-nef"),
+    ("orf", "image/x-olympus-orf"),
+    ("raf", "image/x-fuji-raf"),
+    ("rw2", "image/x-panasonic-rw2"),
+];
+
+/// Check if `path` is a camera raw file, judging by its extension.
+pub fn is_raw<S: AsRef<OsStr> + ?Sized>(path: &S) -> bool {
+    raw_format(Path::new(path)).is_some()
+}
+
+fn raw_format(path: &Path) -> Option<FileFormat> {
+    let suffix = path.extension()?.to_str()?;
+    RAW_FORMATS
+        .iter()
+        .find(|(ext, _)| suffix.eq_ignore_ascii_case(ext))
+        .map(|&(format, mime)| FileFormat { format, mime })
+}
+
+/// The format of an image file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileFormat {
+    /// The preferred file name extension for the format, e.g. "jpg".
+    pub format: &'static str,
+    pub mime: &'static str,
+}
+
+impl FileFormat {
+    /// Detect the format of the image file at `path`.
+    ///
+    /// Camera raw files are known by their extension, other files by
+    /// their content.  Returns None for a file in an unknown format.
+    pub fn of(path: &Path) -> io::Result<Option<FileFormat>> {
+        if let Some(raw) = raw_format(path) {
+            return Ok(Some(raw));
+        }
+        if let Some((format, mime)) =
+            mp4::file_type(&mut fs::File::open(path)?)?
+        {
+            return Ok(Some(FileFormat { format, mime }));
+        }
+        let reader = image::io::Reader::open(path)?.with_guessed_format()?;
+        Ok(reader.format().map(|format| FileFormat {
+            format: format.extensions_str()[0],
+            mime: image_mime(format),
+        }))
+    }
+}
+
+fn image_mime(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Png => "image/png",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Tiff => "image/tiff",
+        ImageFormat::Bmp => "image/bmp",
+        ImageFormat::Ico => "image/x-icon",
+        ImageFormat::Avif => "image/avif",
+        ImageFormat::Pnm => "image/x-portable-anymap",
+        ImageFormat::Tga => "image/x-tga",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The paths that a jpeg made from the raw file `path` may have.
+pub fn jpeg_siblings(path: &str) -> Vec<String> {
+    let path = Path::new(path);
+    ["jpg", "JPG", "jpeg", "JPEG"]
+        .iter()
+        .filter_map(|ext| path.with_extension(ext).to_str().map(String::from))
+        .collect()
+}
+
+/// The paths that the raw file of the jpeg `path` may have.
+///
+/// That is none, if `path` is not a jpeg.
+pub fn raw_siblings(path: &str) -> Vec<String> {
+    let path = Path::new(path);
+    let is_jpeg = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ["jpg", "jpeg"].contains(&&*ext.to_lowercase()));
+    if !is_jpeg {
+        return Vec::new();
+    }
+    RAW_FORMATS
+        .iter()
+        .flat_map(|(ext, _)| vec![ext.to_string(), ext.to_uppercase()])
+        .filter_map(|ext| path.with_extension(ext).to_str().map(String::from))
+        .collect()
+}
+
+#[test]
+fn raw_extensions() {
+    assert!(is_raw("2021/IMG_4711.CR2"));
+    assert!(is_raw("2021/DSC_0042.nef"));
+    assert!(is_raw("2021/x.Arw"));
+    assert!(!is_raw("2021/IMG_4711.jpg"));
+    assert!(!is_raw("2021/cr2"));
+}
+
+#[test]
+fn jpeg_siblings_of_raw() {
+    assert_eq!(
+        jpeg_siblings("a.b/IMG_4711.CR2"),
+        [
+            "a.b/IMG_4711.jpg",
+            "a.b/IMG_4711.JPG",
+            "a.b/IMG_4711.jpeg",
+            "a.b/IMG_4711.JPEG"
+        ],
+    );
+}
+
+#[test]
+fn raw_siblings_of_jpeg() {
+    let raws = raw_siblings("a.b/IMG_4711.JPG");
+    assert_eq!(raws.len(), 2 * RAW_FORMATS.len());
+    assert!(raws.contains(&"a.b/IMG_4711.CR2".to_string()));
+    assert!(raws.contains(&"a.b/IMG_4711.nef".to_string()));
+    assert!(raws.iter().all(is_raw));
+    assert!(raw_siblings("a.b/IMG_4711.png").is_empty());
+    assert!(raw_siblings("a.b/IMG_4711.CR2").is_empty());
+}
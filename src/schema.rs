@@ -10,6 +10,15 @@ table! {
         id -> Int4,
         manufacturer -> Varchar,
         model -> Varchar,
+        attribution_id -> Nullable<Int4>,
+    }
+}
+
+table! {
+    lenses (id) {
+        id -> Int4,
+        manufacturer -> Varchar,
+        model -> Varchar,
     }
 }
 
@@ -57,6 +66,28 @@ table! {
         attribution_id -> Nullable<Int4>,
         width -> Int4,
         height -> Int4,
+        file_size -> Nullable<Int8>,
+        file_mtime -> Nullable<Timestamp>,
+        file_inode -> Nullable<Int8>,
+        content_hash -> Nullable<Varchar>,
+        is_missing -> Bool,
+        raw_of -> Nullable<Int4>,
+        dhash -> Nullable<Int8>,
+        format -> Nullable<Varchar>,
+        mime_type -> Nullable<Varchar>,
+        duration -> Nullable<Int4>,
+        utc_offset -> Nullable<Int4>,
+        time_shift -> Int4,
+        lens_id -> Nullable<Int4>,
+        exposure_time -> Nullable<Float4>,
+        f_number -> Nullable<Float4>,
+        iso -> Nullable<Int4>,
+        focal_length -> Nullable<Float4>,
+        flash -> Nullable<Bool>,
+        flip -> Bool,
+        xmp_size -> Nullable<Int8>,
+        xmp_mtime -> Nullable<Timestamp>,
+        dhash_failed -> Bool,
     }
 }
 
@@ -95,6 +126,7 @@ table! {
     }
 }
 
+joinable!(cameras -> attributions (attribution_id));
 joinable!(photo_people -> people (person_id));
 joinable!(photo_people -> photos (photo_id));
 joinable!(photo_places -> photos (photo_id));
@@ -103,11 +135,13 @@ joinable!(photo_tags -> photos (photo_id));
 joinable!(photo_tags -> tags (tag_id));
 joinable!(photos -> attributions (attribution_id));
 joinable!(photos -> cameras (camera_id));
+joinable!(photos -> lenses (lens_id));
 joinable!(positions -> photos (photo_id));
 
 allow_tables_to_appear_in_same_query!(
     attributions,
     cameras,
+    lenses,
     people,
     photo_people,
     photo_places,
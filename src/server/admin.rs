@@ -1,21 +1,44 @@
 //! Admin-only views, generally called by javascript.
 use super::{not_found, permission_denied, redirect_to_img, Context};
-use crate::models::{Coord, Photo, SizeTag};
+use crate::adm::copyposition::{
+    find_matches, set_position, unpositioned_on_day, DEFAULT_WINDOW,
+};
+use crate::adm::shifttime::{
+    format_shift, parse_shift, parse_since, parse_until, shift_photos,
+    Selection,
+};
+use crate::models::{Camera, Coord, Photo, ScaledFormat, SizeTag};
+use crate::schema::photos::dsl as p;
+use crate::templates::{self, RenderRucte};
+use chrono::{Duration, NaiveDateTime};
 use diesel::{self, prelude::*};
 use log::{info, warn};
 use serde::Deserialize;
-use slug::slugify;
 use warp::filters::BoxedFilter;
 use warp::http::response::Builder;
 use warp::reply::Response;
 use warp::{Filter, Rejection, Reply};
 
 pub fn routes(s: BoxedFilter<(Context,)>) -> BoxedFilter<(impl Reply,)> {
-    use warp::{body::form, path, post};
-    let route = path("grade")
+    use warp::filters::query::query;
+    use warp::path::end;
+    use warp::{body::form, get, path, post};
+    let copy_form = path("copypos")
+        .and(end())
+        .and(s.clone())
+        .and(query())
+        .map(copy_position_form);
+    let shift_form = path("shifttime")
+        .and(end())
+        .and(s.clone())
+        .and(query())
+        .map(shift_time_form);
+    let route = path("copypos")
         .and(s.clone())
         .and(form())
-        .and_then(set_grade)
+        .and_then(copy_position)
+        .or(path("grade").and(s.clone()).and(form()).and_then(set_grade))
+        .unify()
         .or(path("locate")
             .and(s.clone())
             .and(form())
@@ -26,37 +49,285 @@ pub fn routes(s: BoxedFilter<(Context,)>) -> BoxedFilter<(impl Reply,)> {
             .and(form())
             .and_then(set_person))
         .unify()
-        .or(path("rotate").and(s.clone()).and(form()).map(rotate))
+        .or(path("rotate").and(s.clone()).and(form()).and_then(rotate))
+        .unify()
+        .or(path("shifttime").and(s.clone()).and(form()).map(shift_time))
         .unify()
         .or(path("tag").and(s).and(form()).and_then(set_tag))
         .unify();
-    post().and(route).boxed()
+    let forms = copy_form.or(shift_form).unify();
+    get().and(forms).or(post().and(route)).unify().boxed()
 }
 
-fn rotate(context: Context, form: RotateForm) -> Response {
+/// Show positions that can be copied from photos taken close in time.
+///
+/// The matches are shown for the photo and for all photos without
+/// position from the same day.
+fn copy_position_form(context: Context, form: CopyPositionForm) -> Response {
     if !context.is_authorized() {
         return permission_denied().unwrap();
     }
-    info!("Should rotate #{} by {}", form.image, form.angle);
-    use crate::schema::photos::dsl::photos;
-    let c = context.db().unwrap();
-    let c: &PgConnection = &c;
-    if let Ok(mut image) = photos.find(form.image).first::<Photo>(c) {
-        let newvalue = (360 + image.rotation + form.angle) % 360;
-        info!("Rotation was {}, setting to {}", image.rotation, newvalue);
-        image.rotation = newvalue;
-        match image.save_changes::<Photo>(c) {
-            Ok(image) => {
-                context.clear_cache(&image.cache_key(SizeTag::Small));
-                context.clear_cache(&image.cache_key(SizeTag::Medium));
-                return Builder::new().body("ok".into()).unwrap();
+    let db = context.db().unwrap();
+    let photo = match p::photos.find(form.image).first::<Photo>(&db) {
+        Ok(photo) => photo,
+        Err(_) => return not_found(&context),
+    };
+    let matches = photo
+        .date
+        .map(|date| unpositioned_on_day(&db, date.date()))
+        .unwrap_or_else(|| Ok(vec![photo.clone()]))
+        .and_then(|photos| find_matches(&db, photos, form.window()));
+    let (message, matches) = match matches {
+        Ok(matches) if matches.is_empty() => (
+            Some("No positioned photos found close in time.".into()),
+            matches,
+        ),
+        Ok(matches) => (None, matches),
+        Err(e) => (Some(e.to_string()), vec![]),
+    };
+    Builder::new()
+        .html(|o| {
+            templates::copy_position(
+                o, &context, &photo, &form, &message, &matches,
+            )
+        })
+        .unwrap()
+}
+
+/// Copy a position to the photo, or to all photos from its day.
+async fn copy_position(
+    context: Context,
+    form: CopyPositionForm,
+) -> WarpResult {
+    if !context.is_authorized() {
+        return permission_denied();
+    }
+    let db = context.db().unwrap();
+    let photo = match p::photos.find(form.image).first::<Photo>(&db) {
+        Ok(photo) => photo,
+        Err(_) => return Ok(not_found(&context)),
+    };
+    let photos = match photo.date.filter(|_| form.day) {
+        Some(date) => unpositioned_on_day(&db, date.date()),
+        None => Ok(vec![photo]),
+    };
+    let matches = match photos
+        .and_then(|photos| find_matches(&db, photos, form.window()))
+    {
+        Ok(matches) => matches,
+        Err(e) => {
+            warn!("Failed to find positions for #{}: {}", form.image, e);
+            return Ok(not_found(&context));
+        }
+    };
+    for m in &matches {
+        if let Err(e) = set_position(&db, m.photo.id, &m.coord) {
+            warn!("Failed to set position of #{}: {}", m.photo.id, e);
+        }
+    }
+    for m in &matches {
+        if let Err(err) = context
+            .overpass()
+            .update_image_places(&context.db_pool(), m.photo.id)
+            .await
+        {
+            warn!("Failed to fetch places: {:?}", err);
+        }
+    }
+    Ok(redirect_to_img(form.image))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CopyPositionForm {
+    pub image: i32,
+    /// Max minutes between the photos.
+    pub window: Option<i64>,
+    /// Set positions for all photos of the day, not just this one.
+    #[serde(default)]
+    day: bool,
+}
+
+impl CopyPositionForm {
+    fn window(&self) -> Duration {
+        self.window
+            .map(Duration::minutes)
+            .unwrap_or_else(|| Duration::seconds(DEFAULT_WINDOW))
+    }
+    pub fn window_minutes(&self) -> i64 {
+        self.window.unwrap_or(DEFAULT_WINDOW / 60)
+    }
+}
+
+/// Show a form for shifting the time of photos, with a preview.
+fn shift_time_form(context: Context, form: ShiftTimeForm) -> Response {
+    if !context.is_authorized() {
+        return permission_denied().unwrap();
+    }
+    let db = context.db().unwrap();
+    let mut messages = Vec::new();
+    let mut preview = Vec::new();
+    if !form.shift.is_empty() {
+        match form.selection().and_then(|s| Ok((s, form.shift()?))) {
+            Ok((selection, shift)) => match selection.load(&db) {
+                Ok(photos) => {
+                    let shift = Duration::seconds(shift.into());
+                    preview = photos
+                        .into_iter()
+                        .filter_map(|p| {
+                            let shifted = p.date? + shift;
+                            Some((p, shifted))
+                        })
+                        .collect();
+                    if preview.is_empty() {
+                        messages.push("No photos selected.".into());
+                    }
+                }
+                Err(e) => messages.push(e.to_string()),
+            },
+            Err(e) => messages.push(e),
+        }
+    }
+    render_shift_time(&context, &db, &form, &messages, &preview)
+}
+
+/// Shift the time of the photos selected in the form.
+fn shift_time(context: Context, form: ShiftTimeForm) -> Response {
+    if !context.is_authorized() {
+        return permission_denied().unwrap();
+    }
+    let db = context.db().unwrap();
+    let message = match form.selection().and_then(|s| Ok((s, form.shift()?))) {
+        Ok((selection, shift)) => {
+            match selection.load(&db).and_then(|photos| {
+                let ids = photos.iter().map(|p| p.id).collect::<Vec<_>>();
+                shift_photos(&db, &ids, shift)
+            }) {
+                Ok(n) => {
+                    format!("Shifted {} photos by {}.", n, format_shift(shift))
+                }
+                Err(e) => {
+                    warn!("Failed to shift photos: {}", e);
+                    format!("Failed to shift photos: {}", e)
+                }
             }
-            Err(error) => {
-                warn!("Failed to save image #{}: {}", image.id, error);
+        }
+        Err(e) => e,
+    };
+    let empty = ShiftTimeForm::default();
+    render_shift_time(&context, &db, &empty, &[message], &[])
+}
+
+fn render_shift_time(
+    context: &Context,
+    db: &PgConnection,
+    form: &ShiftTimeForm,
+    messages: &[String],
+    preview: &[(Photo, NaiveDateTime)],
+) -> Response {
+    use crate::schema::cameras::dsl as c;
+    let cameras = c::cameras
+        .order((c::manufacturer, c::model))
+        .load::<Camera>(db)
+        .unwrap_or_default();
+    Builder::new()
+        .html(|o| {
+            templates::shift_time(
+                o, context, &cameras, form, messages, preview,
+            )
+        })
+        .unwrap()
+}
+
+/// The form for shifting time, all fields as they are entered.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ShiftTimeForm {
+    pub camera: String,
+    pub path: String,
+    pub since: String,
+    pub until: String,
+    pub shift: String,
+}
+
+impl ShiftTimeForm {
+    pub fn camera_id(&self) -> Option<i32> {
+        self.camera.parse().ok()
+    }
+    pub fn shift(&self) -> Result<i32, String> {
+        parse_shift(&self.shift)
+    }
+    fn selection(&self) -> Result<Selection, String> {
+        let time = |s: &str, parse: fn(&str) -> Result<_, _>| {
+            if s.is_empty() {
+                Ok(None)
+            } else {
+                parse(s).map(Some)
             }
+        };
+        let selection = Selection {
+            camera_id: self.camera_id(),
+            path: Some(self.path.clone()).filter(|p| !p.is_empty()),
+            since: time(&self.since, parse_since)?,
+            until: time(&self.until, parse_until)?,
+        };
+        if selection.is_empty() {
+            return Err("Select a camera, path or time range.".into());
         }
+        Ok(selection)
     }
-    not_found(&context)
+}
+
+async fn rotate(context: Context, form: RotateForm) -> WarpResult {
+    if !context.is_authorized() {
+        return permission_denied();
+    }
+    info!("Should rotate #{} by {}", form.image, form.angle);
+    let image = match rotate_photo(&context, &form) {
+        Some(image) => image,
+        None => return Ok(not_found(&context)),
+    };
+    // The marks to clear depend on the attribution of the photo.
+    let attribution =
+        context.db().ok().and_then(|db| image.load_attribution(&db));
+    for format in &ScaledFormat::ALL {
+        let format = *format;
+        let keys = [SizeTag::Small, SizeTag::Medium]
+            .iter()
+            .map(|size| (image.cache_key(*size, format), size.px()))
+            .chain(
+                context
+                    .size_variants()
+                    .iter()
+                    .map(|v| (image.variant_cache_key(v, format), v.px)),
+            )
+            .collect::<Vec<_>>();
+        for (key, px) in keys {
+            let mark = context
+                .watermark()
+                .and_then(|w| w.mark(px, || attribution.clone()));
+            if let Some(mark) = mark {
+                context
+                    .clear_cache(&Photo::watermarked_key(&key, &mark))
+                    .await;
+            }
+            context.clear_cache(&key).await;
+        }
+    }
+    Ok(Builder::new().body("ok".into()).unwrap())
+}
+
+fn rotate_photo(context: &Context, form: &RotateForm) -> Option<Photo> {
+    use crate::schema::photos::dsl::photos;
+    let c = context.db().unwrap();
+    let c: &PgConnection = &c;
+    let mut image = photos.find(form.image).first::<Photo>(c).ok()?;
+    let newvalue = (360 + image.rotation + form.angle) % 360;
+    info!("Rotation was {}, setting to {}", image.rotation, newvalue);
+    image.rotation = newvalue;
+    image
+        .save_changes::<Photo>(c)
+        .map_err(|e| warn!("Failed to save image #{}: {}", image.id, e))
+        .ok()
 }
 
 #[derive(Deserialize)]
@@ -73,20 +344,8 @@ async fn set_tag(context: Context, form: TagForm) -> WarpResult {
     }
     let c = context.db().unwrap();
     use crate::models::{PhotoTag, Tag};
-    let tag = {
-        use crate::schema::tags::dsl::*;
-        tags.filter(tag_name.ilike(&form.tag))
-            .first::<Tag>(&c)
-            .or_else(|_| {
-                diesel::insert_into(tags)
-                    .values((
-                        tag_name.eq(&form.tag),
-                        slug.eq(&slugify(&form.tag)),
-                    ))
-                    .get_result::<Tag>(&c)
-            })
-            .expect("Find or create tag")
-    };
+    let tag =
+        Tag::get_or_create_name(&c, &form.tag).expect("Find or create tag");
     use crate::schema::photo_tags::dsl::*;
     let q = photo_tags
         .filter(photo_id.eq(form.image))
@@ -183,15 +442,7 @@ async fn set_location(context: Context, form: CoordForm) -> WarpResult {
     let coord = form.coord();
     info!("Should set location of #{} to {:?}.", image, coord);
 
-    let (lat, lng) = ((coord.x * 1e6) as i32, (coord.y * 1e6) as i32);
-    use crate::schema::positions::dsl::*;
-    use diesel::insert_into;
-    insert_into(positions)
-        .values((photo_id.eq(image), latitude.eq(lat), longitude.eq(lng)))
-        .on_conflict(photo_id)
-        .do_update()
-        .set((latitude.eq(lat), longitude.eq(lng)))
-        .execute(&context.db().unwrap())
+    set_position(&context.db().unwrap(), image, &coord)
         .expect("Insert image position");
     match context
         .overpass()
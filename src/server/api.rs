@@ -1,7 +1,7 @@
 //! API views
 use super::login::LoginForm;
-use super::Context;
-use crate::models::{Photo, SizeTag};
+use super::{srcset, Context};
+use crate::models::{Photo, SizeTag, SizeVariant};
 use diesel::{self, prelude::*, result::Error as DbError, update};
 use log::warn;
 use serde::{Deserialize, Serialize};
@@ -119,7 +119,7 @@ fn get_img(context: Context, q: ImgQuery) -> ApiResult<GetImgResult> {
     if !context.is_authorized() && !img.is_public() {
         return Err(NOT_FOUND);
     }
-    Ok(GetImgResult::for_img(&img))
+    Ok(GetImgResult::for_img(&img, context.size_variants()))
 }
 
 fn make_public(context: Context, q: ImgQuery) -> ApiResult<GetImgResult> {
@@ -136,7 +136,7 @@ fn make_public(context: Context, q: ImgQuery) -> ApiResult<GetImgResult> {
     let img = update(p::photos.find(img.id))
         .set(p::is_public.eq(true))
         .get_result(&db)?;
-    Ok(GetImgResult::for_img(&img))
+    Ok(GetImgResult::for_img(&img, context.size_variants()))
 }
 
 struct ApiError {
@@ -194,10 +194,10 @@ struct GetImgResult {
 }
 
 impl GetImgResult {
-    fn for_img(img: &Photo) -> Self {
+    fn for_img(img: &Photo, variants: &[SizeVariant]) -> Self {
         GetImgResult {
-            small: ImgLink::new(img, SizeTag::Small),
-            medium: ImgLink::new(img, SizeTag::Medium),
+            small: ImgLink::new(img, SizeTag::Small, variants),
+            medium: ImgLink::new(img, SizeTag::Medium, variants),
             public: img.is_public,
         }
     }
@@ -208,15 +208,29 @@ struct ImgLink {
     url: String,
     width: u32,
     height: u32,
+    /// All scaled images, for a srcset attribute.
+    srcset: String,
 }
 
 impl ImgLink {
-    fn new(img: &Photo, size: SizeTag) -> Self {
+    fn new(img: &Photo, size: SizeTag, variants: &[SizeVariant]) -> Self {
         let (width, height) = img.get_size(size);
         ImgLink {
-            url: format!("/img/{}-{}.jpg", img.id, size.tag()),
+            url: format!(
+                "/img/{}-{}.jpg?v={}",
+                img.id,
+                size.tag(),
+                img.image_version(),
+            ),
             width,
             height,
+            srcset: srcset(
+                img.id,
+                &img.image_version(),
+                size,
+                width,
+                variants,
+            ),
         }
     }
 }
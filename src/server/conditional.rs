@@ -0,0 +1,174 @@
+//! Conditional requests, answered with 304 Not Modified when the
+//! client already has the current version of a resource.
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use std::convert::Infallible;
+use warp::filters::header::optional;
+use warp::http::response::Builder;
+use warp::http::{header, StatusCode};
+use warp::hyper::Body;
+use warp::reply::Response;
+use warp::Filter;
+
+/// The conditions of a request.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    if_range: Option<String>,
+}
+
+/// Get the `If-None-Match`, `If-Modified-Since` and `If-Range` headers.
+pub fn conditions(
+) -> impl Filter<Extract = (Conditions,), Error = Infallible> + Clone {
+    optional("if-none-match")
+        .and(optional("if-modified-since"))
+        .and(optional("if-range"))
+        .map(|if_none_match, if_modified_since, if_range| Conditions {
+            if_none_match,
+            if_modified_since,
+            if_range,
+        })
+        .or(warp::any().map(Conditions::default))
+        .unify()
+}
+
+impl Conditions {
+    /// True if the client has the version with `etag`, modified at
+    /// `modified`.
+    ///
+    /// As in RFC 7232, If-Modified-Since is only used when there is
+    /// no If-None-Match.
+    pub fn is_fresh(
+        &self,
+        etag: &str,
+        modified: Option<NaiveDateTime>,
+    ) -> bool {
+        if let Some(tags) = &self.if_none_match {
+            return tags.split(',').map(str::trim).any(|tag| {
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            });
+        }
+        match (&self.if_modified_since, modified) {
+            (Some(since), Some(modified)) => {
+                DateTime::parse_from_rfc2822(since)
+                    .map(|since| {
+                        modified.and_utc().timestamp() <= since.timestamp()
+                    })
+                    .unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+
+    /// True if a Range request should be honored.
+    ///
+    /// That is unless an If-Range condition tells that the client has
+    /// parts of another version, which calls for the entire file.
+    /// An etag must match exactly and a date must be the modification
+    /// time.
+    pub fn range_applies(
+        &self,
+        etag: &str,
+        modified: Option<NaiveDateTime>,
+    ) -> bool {
+        match self.if_range.as_deref().map(str::trim) {
+            None => true,
+            Some(tag) if tag.starts_with('"') => tag == etag,
+            Some(date) => match (DateTime::parse_from_rfc2822(date), modified)
+            {
+                (Ok(date), Some(modified)) => {
+                    modified.and_utc().timestamp() == date.timestamp()
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Set the `ETag` and (if known) `Last-Modified` headers.
+pub fn with_validators(
+    builder: Builder,
+    etag: &str,
+    modified: Option<NaiveDateTime>,
+) -> Builder {
+    let builder = builder.header(header::ETAG, etag);
+    match modified {
+        Some(modified) => {
+            builder.header(header::LAST_MODIFIED, http_date(modified))
+        }
+        None => builder,
+    }
+}
+
+/// Respond with 304 Not Modified, keeping the headers of `builder`.
+pub fn not_modified(builder: Builder) -> Response {
+    builder
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Format a time in UTC as a http date.
+fn http_date(time: NaiveDateTime) -> String {
+    Utc.from_utc_datetime(&time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+#[cfg(test)]
+fn at(h: u32, m: u32, s: u32) -> NaiveDateTime {
+    chrono::NaiveDate::from_ymd_opt(2021, 5, 16)
+        .and_then(|d| d.and_hms_opt(h, m, s))
+        .unwrap()
+}
+
+#[test]
+fn fresh_by_etag() {
+    let etag = "\"17-s-v1.jpg\"";
+    let check = |tags: &str| {
+        Conditions {
+            if_none_match: Some(tags.into()),
+            if_modified_since: Some("Sun, 16 May 2021 13:00:00 GMT".into()),
+            if_range: None,
+        }
+        .is_fresh(etag, Some(at(12, 0, 0)))
+    };
+    assert!(check("\"17-s-v1.jpg\""));
+    assert!(check("\"17-s-v0.jpg\", W/\"17-s-v1.jpg\""));
+    assert!(check("*"));
+    assert!(!check("\"17-s-v0.jpg\""));
+}
+
+#[test]
+fn fresh_by_date() {
+    let check = |since: &str| {
+        Conditions {
+            if_none_match: None,
+            if_modified_since: Some(since.into()),
+            if_range: None,
+        }
+        .is_fresh("\"x\"", Some(at(12, 0, 0)))
+    };
+    assert!(check(&http_date(at(12, 0, 0))));
+    assert!(check("Sun, 16 May 2021 14:00:00 +0200"));
+    assert!(!check("Sun, 16 May 2021 11:59:59 GMT"));
+    assert!(!check("yesterday"));
+    assert!(!Conditions::default().is_fresh("\"x\"", Some(at(12, 0, 0))));
+}
+
+#[test]
+fn range_by_if_range() {
+    let check = |if_range: Option<&str>| {
+        Conditions {
+            if_range: if_range.map(Into::into),
+            ..Conditions::default()
+        }
+        .range_applies("\"17-l-v1.jpg\"", Some(at(12, 0, 0)))
+    };
+    assert!(check(None));
+    assert!(check(Some("\"17-l-v1.jpg\"")));
+    assert!(!check(Some("W/\"17-l-v1.jpg\"")));
+    assert!(!check(Some("\"17-l-v0.jpg\"")));
+    assert!(check(Some("Sun, 16 May 2021 12:00:00 GMT")));
+    assert!(!check(Some("Sun, 16 May 2021 11:00:00 GMT")));
+}
@@ -1,22 +1,24 @@
 use super::Args;
+use crate::cache::{self, Cache};
 use crate::dbopt::{PgPool, PooledPg};
 use crate::fetch_places::OverpassOpt;
+use crate::metadata::MetadataOpt;
+use crate::models::SizeVariant;
 use crate::photosdir::PhotosDir;
-use diesel::r2d2::{Pool, PooledConnection};
+use crate::watermark::Watermark;
 use log::{debug, warn};
 use medallion::{Header, Payload, Token};
 use r2d2_memcache::r2d2::Error;
-use r2d2_memcache::MemcacheConnectionManager;
 use std::future::Future;
+use std::io;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::task::spawn_blocking;
 use warp::filters::{cookie, header, BoxedFilter};
 use warp::path::{self, FullPath};
 use warp::{self, Filter};
 
 pub type ContextFilter = BoxedFilter<(Context,)>;
-type MemcachePool = Pool<MemcacheConnectionManager>;
-type PooledMemcache = PooledConnection<MemcacheConnectionManager>;
 
 pub fn create_session_filter(args: &Args) -> ContextFilter {
     let global = Arc::new(GlobalContext::new(args));
@@ -47,24 +49,29 @@ pub fn create_session_filter(args: &Args) -> ContextFilter {
 struct GlobalContext {
     db_pool: PgPool,
     photosdir: PhotosDir,
-    memcache_pool: MemcachePool,
+    cache: Box<dyn Cache>,
     jwt_secret: String,
     overpass: OverpassOpt,
+    size_variants: Vec<SizeVariant>,
+    metadata: MetadataOpt,
+    public_originals: bool,
+    watermark: Option<Watermark>,
 }
 
 impl GlobalContext {
     fn new(args: &Args) -> Self {
-        let mc_manager =
-            MemcacheConnectionManager::new(args.cache.memcached_url.as_ref());
+        let mut size_variants = args.size_variants.clone();
+        size_variants.sort_by_key(|variant| variant.px);
         GlobalContext {
             db_pool: args.db.create_pool().expect("Posgresql pool"),
             photosdir: PhotosDir::new(&args.photos.photos_dir),
-            memcache_pool: Pool::builder()
-                .connection_timeout(Duration::from_secs(1))
-                .build(mc_manager)
-                .expect("Memcache pool"),
+            cache: args.cache.open().expect("Cache"),
             jwt_secret: args.jwt_key.clone(),
             overpass: args.overpass.clone(),
+            size_variants,
+            metadata: args.metadata.clone(),
+            public_originals: args.public_originals,
+            watermark: args.watermark.load().expect("Watermark"),
         }
     }
 
@@ -98,9 +105,6 @@ impl GlobalContext {
             .sub
             .ok_or_else(|| "User missing in jwt claims".to_string())
     }
-    fn cache(&self) -> Result<PooledMemcache, Error> {
-        self.memcache_pool.get()
-    }
 }
 
 fn verify_token(
@@ -112,7 +116,7 @@ fn verify_token(
         .map_err(|e| format!("Failed to verify token {:?}: {}", token, e))
 }
 
-/// The request context, providing database, memcache and authorized user.
+/// The request context, providing database, cache and authorized user.
 pub struct Context {
     global: Arc<GlobalContext>,
     path: FullPath,
@@ -144,47 +148,77 @@ impl Context {
         F: FnOnce() -> R,
         R: Future<Output = Result<Vec<u8>, E>>,
     {
-        match self.global.cache() {
-            Ok(client) => {
-                match client.get(key) {
-                    Ok(Some(data)) => {
-                        debug!("Cache: {} found", key);
-                        return Ok(data);
-                    }
-                    Ok(None) => {
-                        debug!("Cache: {} not found", key);
-                    }
-                    Err(err) => {
-                        warn!("Cache: get {} failed: {:?}", key, err);
-                    }
-                }
-                let data = calculate().await?;
-                match client.set(key, &data[..], 7 * 24 * 60 * 60) {
-                    Ok(()) => debug!("Cache: stored {}", key),
-                    Err(err) => warn!("Cache: Error storing {}: {}", key, err),
-                }
-                Ok(data)
+        let k = key.to_string();
+        match self.with_cache(move |cache| cache.get(&k)).await {
+            Ok(Some(data)) => {
+                debug!("Cache: {} found", key);
+                return Ok(data);
+            }
+            Ok(None) => {
+                debug!("Cache: {} not found", key);
             }
             Err(err) => {
-                warn!("Error connecting to memcache: {}", err);
-                calculate().await
+                warn!("Cache: get {} failed: {}", key, err);
             }
         }
+        let data = calculate().await?;
+        let expire = Duration::from_secs(7 * 24 * 60 * 60);
+        let k = key.to_string();
+        let stored = data.clone();
+        let set =
+            move |cache: &dyn Cache| cache.set(&k, &stored, Some(expire));
+        match self.with_cache(set).await {
+            Ok(()) => debug!("Cache: stored {}", key),
+            Err(err) => warn!("Cache: Error storing {}: {}", key, err),
+        }
+        Ok(data)
     }
-    pub fn clear_cache(&self, key: &str) {
-        if let Ok(client) = self.global.cache() {
-            match client.delete(key) {
-                Ok(flag) => debug!("Cache: deleted {}: {:?}", key, flag),
-                Err(e) => warn!("Cache: Failed to delete {}: {}", key, e),
-            }
+    pub async fn clear_cache(&self, key: &str) {
+        let k = key.to_string();
+        match self.with_cache(move |cache| cache.delete(&k)).await {
+            Ok(flag) => debug!("Cache: deleted {}: {:?}", key, flag),
+            Err(e) => warn!("Cache: Failed to delete {}: {}", key, e),
         }
     }
+    /// Run `f` on the cache in a blocking thread.
+    ///
+    /// The disk cache does file I/O and memcached blocking network
+    /// calls, which should not stall the async workers.
+    async fn with_cache<F, T>(&self, f: F) -> Result<T, cache::Error>
+    where
+        F: FnOnce(&dyn Cache) -> Result<T, cache::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let global = self.global.clone();
+        spawn_blocking(move || f(global.cache.as_ref()))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e).into()))
+    }
     pub fn photos(&self) -> &PhotosDir {
         &self.global.photosdir
     }
     pub fn overpass(&self) -> &OverpassOpt {
         &self.global.overpass
     }
+    /// The configured extra sizes of scaled images, smallest first.
+    pub fn size_variants(&self) -> &[SizeVariant] {
+        &self.global.size_variants
+    }
+    /// What metadata to embed in scaled images.
+    pub fn metadata(&self) -> &MetadataOpt {
+        &self.global.metadata
+    }
+    /// True if anyone may get originals of public photos.
+    pub fn public_originals(&self) -> bool {
+        self.global.public_originals
+    }
+    /// The watermark for images shown to anonymous users, if any.
+    pub fn watermark(&self) -> Option<&Watermark> {
+        self.global.watermark.as_ref()
+    }
+    pub fn size_variant(&self, name: &str) -> Option<&SizeVariant> {
+        self.size_variants().iter().find(|v| v.name == name)
+    }
 
     pub fn make_token(&self, user: &str) -> Option<String> {
         let header: Header = Default::default();
@@ -1,9 +1,18 @@
+use super::conditional::{not_modified, with_validators, Conditions};
+use super::stream::{content_disposition, stream_file, stream_patched_file};
 use super::BuilderExt;
-use super::{error_response, not_found, Context};
-use crate::models::{Photo, SizeTag};
-use crate::photosdir::{get_scaled_jpeg, ImageLoadFailed};
+use super::{error_response, not_found, redirect, Context};
+use crate::metadata::strip_private;
+use crate::models::{Photo, ScaledFormat, SizeTag, SizeVariant};
+use crate::mp4::PrivateAtoms;
+use crate::photosdir::{get_scaled_image, ImageLoadFailed};
+use crate::watermark::Mark;
 use diesel::prelude::*;
+use log::warn;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use tokio::task::spawn_blocking;
 use warp::http::response::Builder;
 use warp::http::{header, StatusCode};
 use warp::reply::Response;
@@ -11,61 +20,298 @@ use warp::Rejection;
 
 pub async fn show_image(
     img: ImgName,
+    accept: Option<String>,
+    range: Option<String>,
+    conditions: Conditions,
     context: Context,
 ) -> Result<Response, Rejection> {
     use crate::schema::photos::dsl::photos;
     let tphoto = photos.find(img.id).first::<Photo>(&context.db().unwrap());
     if let Ok(tphoto) = tphoto {
+        // There are no scaled images of videos.
+        if tphoto.is_missing || (tphoto.is_video() && !img.is_large()) {
+            return Ok(not_found(&context));
+        }
         if context.is_authorized() || tphoto.is_public() {
-            if img.size == SizeTag::Large {
-                if context.is_authorized() {
-                    use std::fs::File;
-                    use std::io::Read;
-                    // TODO: This should be done in a more async-friendly way.
+            if img.is_large() {
+                let public = !context.is_authorized();
+                if !public || context.public_originals() {
+                    if img.ext != tphoto.extension() {
+                        return Ok(redirect(&format!(
+                            "/img/{}-l.{}",
+                            tphoto.id,
+                            tphoto.extension(),
+                        )));
+                    }
+                    // Public originals differ by the stripped metadata.
+                    let size = if public { "lp" } else { "l" };
+                    let etag = etag(&tphoto, size, tphoto.extension());
+                    let modified = tphoto.file_mtime;
+                    let builder =
+                        with_validators(Builder::new(), &etag, modified)
+                            .far_expires();
+                    if conditions.is_fresh(&etag, modified) {
+                        return Ok(not_modified(builder));
+                    }
                     let path = context.photos().get_raw_path(&tphoto);
-                    let mut buf = Vec::new();
-                    if File::open(path)
-                        .map(|mut f| f.read_to_end(&mut buf))
-                        .is_ok()
-                    {
-                        return Ok(Builder::new()
-                            .status(StatusCode::OK)
-                            .header(
-                                header::CONTENT_TYPE,
-                                mime::IMAGE_JPEG.as_ref(),
-                            )
-                            .far_expires()
-                            .body(buf.into())
-                            .unwrap());
-                    } else {
-                        return Ok(error_response(
-                            StatusCode::INTERNAL_SERVER_ERROR,
+                    let builder = builder.header(
+                        header::CONTENT_DISPOSITION,
+                        content_disposition("inline", &path),
+                    );
+                    if public {
+                        return Ok(public_original(
+                            &context, &tphoto, builder, &path,
                         )
-                        .unwrap());
+                        .await);
                     }
+                    let range = range
+                        .filter(|_| conditions.range_applies(&etag, modified));
+                    let mime = tphoto.content_type();
+                    return Ok(
+                        match stream_file(builder, &path, mime, range).await {
+                            Ok(response) => response,
+                            Err(e) => file_error(&context, &tphoto, e),
+                        },
+                    );
                 }
             } else {
-                let data = get_image_data(&context, &tphoto, img.size)
-                    .await
-                    .expect("Get image data");
-                return Ok(Builder::new()
-                    .status(StatusCode::OK)
-                    .header(header::CONTENT_TYPE, mime::IMAGE_JPEG.as_ref())
-                    .far_expires()
-                    .body(data.into())
-                    .unwrap());
+                let format = scaled_format(accept.as_deref());
+                let (px, key) = match img.size.as_str() {
+                    "s" => (
+                        SizeTag::Small.px(),
+                        tphoto.cache_key(SizeTag::Small, format),
+                    ),
+                    "m" => (
+                        SizeTag::Medium.px(),
+                        tphoto.cache_key(SizeTag::Medium, format),
+                    ),
+                    name => match context.size_variant(name) {
+                        Some(v) => (v.px, tphoto.variant_cache_key(v, format)),
+                        None => return Ok(not_found(&context)),
+                    },
+                };
+                let mark = watermark(&context, &tphoto, px);
+                let (size, key) = match &mark {
+                    Some(mark) => (
+                        format!("{}w{}", img.size, mark.key()),
+                        Photo::watermarked_key(&key, mark),
+                    ),
+                    None => (img.size.clone(), key),
+                };
+                // The scaled image depends on the rotation, so it has
+                // no meaningful modification time.
+                let etag = etag(&tphoto, &size, format.ext());
+                let vary = if context.watermark().is_some() {
+                    "accept, cookie, authorization"
+                } else {
+                    "accept"
+                };
+                let builder = with_validators(Builder::new(), &etag, None)
+                    .header(header::VARY, vary)
+                    .far_expires();
+                if conditions.is_fresh(&etag, None) {
+                    return Ok(not_modified(builder));
+                }
+                let data =
+                    get_image_data(&context, &tphoto, px, format, &key, mark);
+                return match data.await {
+                    Ok(data) => Ok(builder
+                        .status(StatusCode::OK)
+                        .header(header::CONTENT_TYPE, format.content_type())
+                        .body(data.into())
+                        .unwrap()),
+                    Err(ImageLoadFailed::File(e)) => {
+                        Ok(file_error(&context, &tphoto, e))
+                    }
+                    Err(e) => {
+                        warn!("Failed to scale #{}: {}", tphoto.id, e);
+                        error_response(StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                };
             }
         }
     }
     Ok(not_found(&context))
 }
 
+/// Respond with an original for anonymous users.
+///
+/// Only jpeg originals are served, with the private metadata removed
+/// by [`strip_private`].
+async fn public_original(
+    context: &Context,
+    photo: &Photo,
+    builder: Builder,
+    path: &Path,
+) -> Response {
+    if photo.extension() != "jpg" {
+        return not_found(context);
+    }
+    match tokio::fs::read(path).await.map(|data| strip_private(&data)) {
+        Ok(Some(data)) => builder
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, photo.content_type())
+            .body(data.into())
+            .unwrap(),
+        Ok(None) => {
+            warn!("Original of #{} is not a jpeg: {}", photo.id, photo.path);
+            not_found(context)
+        }
+        Err(e) => file_error(context, photo, e),
+    }
+}
+
+/// Download the raw file of a photo.
+///
+/// Only for authorized users, the raw file is never public.
+pub async fn show_raw(
+    id: i32,
+    range: Option<String>,
+    conditions: Conditions,
+    context: Context,
+) -> Result<Response, Rejection> {
+    if !context.is_authorized() {
+        return Ok(not_found(&context));
+    }
+    use crate::schema::photos::dsl::photos;
+    let raw = photos
+        .find(id)
+        .first::<Photo>(&context.db().unwrap())
+        .ok()
+        .and_then(|photo| photo.load_raw(&context.db().unwrap()));
+    if let Some(raw) = raw {
+        let etag = etag(&raw, "raw", raw.extension());
+        let modified = raw.file_mtime;
+        let builder =
+            with_validators(Builder::new(), &etag, modified).far_expires();
+        if conditions.is_fresh(&etag, modified) {
+            return Ok(not_modified(builder));
+        }
+        let path = context.photos().get_raw_path(&raw);
+        let builder = builder.header(
+            header::CONTENT_DISPOSITION,
+            content_disposition("attachment", &path),
+        );
+        let range =
+            range.filter(|_| conditions.range_applies(&etag, modified));
+        let mime = raw
+            .mime_type
+            .as_deref()
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref());
+        return Ok(match stream_file(builder, &path, mime, range).await {
+            Ok(response) => response,
+            Err(e) => file_error(&context, &raw, e),
+        });
+    }
+    Ok(not_found(&context))
+}
+
+/// Stream a video, supporting range requests so the client can seek.
+///
+/// Anonymous users get the video with the atoms that may tell where
+/// it was made hidden, see [`PrivateAtoms`].
+pub async fn show_video(
+    id: i32,
+    range: Option<String>,
+    context: Context,
+) -> Result<Response, Rejection> {
+    use crate::schema::photos::dsl::photos;
+    let video = photos.find(id).first::<Photo>(&context.db().unwrap());
+    if let Ok(video) = video {
+        if video.is_video()
+            && !video.is_missing
+            && (context.is_authorized() || video.is_public())
+        {
+            let path = context.photos().get_raw_path(&video);
+            let builder = Builder::new()
+                .header(header::VARY, "cookie, authorization")
+                .far_expires();
+            let public = !context.is_authorized();
+            let mime = video.content_type();
+            return Ok(
+                match stream_video(builder, path, mime, range, public).await {
+                    Ok(response) => response,
+                    Err(e) => file_error(&context, &video, e),
+                },
+            );
+        }
+    }
+    Ok(not_found(&context))
+}
+
+/// Stream the video at `path`, with the private atoms hidden if it
+/// is for `public` (anonymous) users.
+///
+/// A video where the private atoms can't be found is not streamed
+/// to the public at all.
+async fn stream_video(
+    builder: Builder,
+    path: PathBuf,
+    mime: &str,
+    range: Option<String>,
+    public: bool,
+) -> io::Result<Response> {
+    if !public {
+        return stream_file(builder, &path, mime, range).await;
+    }
+    let find_path = path.clone();
+    let private = spawn_blocking(move || PrivateAtoms::find(&find_path))
+        .await
+        .map_err(io::Error::other)??;
+    stream_patched_file(builder, &path, mime, range, move |pos, buf| {
+        private.hide(pos, buf)
+    })
+    .await
+}
+
+/// A strong etag for an image of `photo`.
+///
+/// The image depends on the size, the format and the version of the
+/// photo, see [`Photo::image_version`].
+fn etag(photo: &Photo, size: &str, ext: &str) -> String {
+    format!(
+        "\"{}-{}-{}.{}\"",
+        photo.id,
+        size,
+        photo.image_version(),
+        ext
+    )
+}
+
+/// Respond to a failure to read the file for a photo.
+///
+/// A file that is gone is reported as not found, rather than as a
+/// server error.  Use `rphotos orphans` to find such photos.
+fn file_error(context: &Context, photo: &Photo, e: io::Error) -> Response {
+    warn!(
+        "Failed to read file for #{} {}: {}",
+        photo.id, photo.path, e
+    );
+    if e.kind() == io::ErrorKind::NotFound {
+        not_found(context)
+    } else {
+        error_response(StatusCode::INTERNAL_SERVER_ERROR).unwrap()
+    }
+}
+
 /// A client-side / url file name for a file.
 /// Someting like 4711-s.jpg
+///
+/// Scaled images are always jpeg, but the original (`-l`) has the
+/// extension of its format, like 4711-l.png.  Besides `s`, `m` and
+/// `l`, the size can be the name of a configured [`SizeVariant`],
+/// like 4711-s2.jpg.
 #[derive(Debug, Eq, PartialEq)]
 pub struct ImgName {
     id: i32,
-    size: SizeTag,
+    size: String,
+    ext: String,
+}
+
+impl ImgName {
+    fn is_large(&self) -> bool {
+        self.size == "l"
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -77,13 +323,26 @@ impl FromStr for ImgName {
         if let Some(pos) = s.find('-') {
             let (num, rest) = s.split_at(pos);
             let id = num.parse().map_err(|_| BadImgName {})?;
-            let size = match rest {
-                "-s.jpg" => SizeTag::Small,
-                "-m.jpg" => SizeTag::Medium,
-                "-l.jpg" => SizeTag::Large,
-                _ => return Err(BadImgName {}),
+            let (size, ext) =
+                rest[1..].split_once('.').ok_or(BadImgName {})?;
+            let is_name = |s: &str| {
+                !s.is_empty()
+                    && s.bytes()
+                        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
             };
-            return Ok(ImgName { id, size });
+            let good_ext = if size == "l" {
+                is_name(ext)
+            } else {
+                ext == "jpg"
+            };
+            if !is_name(size) || !good_ext {
+                return Err(BadImgName {});
+            }
+            return Ok(ImgName {
+                id,
+                size: size.into(),
+                ext: ext.into(),
+            });
         }
         Err(BadImgName {})
     }
@@ -95,28 +354,222 @@ fn parse_good_imgname() {
         "4711-s.jpg".parse(),
         Ok(ImgName {
             id: 4711,
-            size: SizeTag::Small,
+            size: "s".into(),
+            ext: "jpg".into(),
+        })
+    )
+}
+
+#[test]
+fn parse_good_imgname_png() {
+    assert_eq!(
+        "4711-l.png".parse(),
+        Ok(ImgName {
+            id: 4711,
+            size: "l".into(),
+            ext: "png".into(),
+        })
+    )
+}
+
+#[test]
+fn parse_good_imgname_variant() {
+    assert_eq!(
+        "4711-s2.jpg".parse(),
+        Ok(ImgName {
+            id: 4711,
+            size: "s2".into(),
+            ext: "jpg".into(),
         })
     )
 }
 
 #[test]
 fn parse_bad_imgname_1() {
-    assert_eq!("4711-q.jpg".parse::<ImgName>(), Err(BadImgName {}))
+    assert_eq!("4711-Q.jpg".parse::<ImgName>(), Err(BadImgName {}))
 }
 #[test]
 fn parse_bad_imgname_2() {
     assert_eq!("blurgel".parse::<ImgName>(), Err(BadImgName {}))
 }
+#[test]
+fn parse_bad_imgname_3() {
+    assert_eq!("4711-s.png".parse::<ImgName>(), Err(BadImgName {}))
+}
+#[test]
+fn parse_bad_imgname_4() {
+    assert_eq!("4711-l.".parse::<ImgName>(), Err(BadImgName {}))
+}
+
+/// Choose the format of a scaled image from an accept header.
+///
+/// The url of a scaled image is the same whatever its format, so
+/// browsers that can show avif or webp get it without knowing in
+/// advance.  Avif is preferred, since it is smallest.  Anything else
+/// gets jpeg.
+fn scaled_format(accept: Option<&str>) -> ScaledFormat {
+    let accepts = |mime: &str| {
+        accept
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                if parts.next() != Some(mime) {
+                    return None;
+                }
+                let q = parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok());
+                Some(q.unwrap_or(1.))
+            })
+            .any(|q| q > 0.)
+    };
+    if accepts("image/avif") {
+        ScaledFormat::Avif
+    } else if accepts("image/webp") {
+        ScaledFormat::WebP
+    } else {
+        ScaledFormat::Jpeg
+    }
+}
 
+#[test]
+fn negotiate_format() {
+    let firefox = "image/avif,image/webp,*/*";
+    assert_eq!(scaled_format(Some(firefox)), ScaledFormat::Avif);
+    let no_avif = "image/avif;q=0,image/webp,*/*";
+    assert_eq!(scaled_format(Some(no_avif)), ScaledFormat::WebP);
+    let no_webp = "image/webp;q=0, image/*;q=0.8";
+    assert_eq!(scaled_format(Some(no_webp)), ScaledFormat::Jpeg);
+    assert_eq!(scaled_format(Some("*/*")), ScaledFormat::Jpeg);
+    assert_eq!(scaled_format(None), ScaledFormat::Jpeg);
+}
+
+/// Get `photo` scaled to at most `px`, cached as `key`.
+///
+/// The `key` must differ between images with and without `mark`.
 async fn get_image_data(
     context: &Context,
     photo: &Photo,
-    size: SizeTag,
+    px: u32,
+    format: ScaledFormat,
+    key: &str,
+    mark: Option<Mark>,
 ) -> Result<Vec<u8>, ImageLoadFailed> {
     let p = context.photos().get_raw_path(photo);
-    let r = photo.rotation;
+    let (r, f) = (photo.rotation, photo.flip);
     context
-        .cached_or(&photo.cache_key(size), || get_scaled_jpeg(p, r, size.px()))
+        .cached_or(key, || {
+            let xmp = public_xmp(context, photo);
+            get_scaled_image(p, r, f, px, format, xmp, mark)
+        })
         .await
 }
+
+/// The watermark for `photo` scaled to `px`, if it should have one.
+///
+/// Only images for anonymous users are marked.
+fn watermark(context: &Context, photo: &Photo, px: u32) -> Option<Mark> {
+    if context.is_authorized() {
+        return None;
+    }
+    context.watermark()?.mark(px, || {
+        let db = context
+            .db()
+            .map_err(|e| warn!("Failed to get db for watermark: {}", e))
+            .ok()?;
+        photo.load_attribution(&db)
+    })
+}
+
+/// The metadata to embed in scaled images of `photo`, if any.
+///
+/// Failing to get it is not worth failing the image for.
+fn public_xmp(context: &Context, photo: &Photo) -> Option<String> {
+    let db = context
+        .db()
+        .map_err(|e| warn!("Failed to get db for metadata: {}", e))
+        .ok()?;
+    context
+        .metadata()
+        .public_xmp(&db, photo)
+        .map_err(|e| warn!("Failed to get metadata for #{}: {}", photo.id, e))
+        .ok()?
+}
+
+/// A srcset for a photo shown as `size`, `width` css pixels wide.
+///
+/// Lists the small and medium images and all configured variants,
+/// with widths relative to the shown size, so the browser can pick
+/// one that fits the screen.  The urls include `version`, see
+/// [`Photo::image_version`].
+pub fn srcset(
+    id: i32,
+    version: &str,
+    size: SizeTag,
+    width: u32,
+    variants: &[SizeVariant],
+) -> String {
+    let mut sizes = [SizeTag::Small, SizeTag::Medium]
+        .iter()
+        .map(|tag| (tag.tag().to_string(), tag.px()))
+        .chain(variants.iter().map(|v| (v.name.clone(), v.px)))
+        .collect::<Vec<_>>();
+    sizes.sort_by_key(|(_, px)| *px);
+    sizes
+        .iter()
+        .map(|(name, px)| {
+            let w = u64::from(width) * u64::from(*px) / u64::from(size.px());
+            format!("/img/{}-{}.jpg?v={} {}w", id, name, version, w)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[test]
+fn srcset_with_variant() {
+    let variants = ["s2=576".parse().unwrap()];
+    assert_eq!(
+        srcset(17, "0-0-1", SizeTag::Small, 200, &variants),
+        "/img/17-s.jpg?v=0-0-1 200w, /img/17-s2.jpg?v=0-0-1 400w, \
+         /img/17-m.jpg?v=0-0-1 750w",
+    );
+}
+
+#[tokio::test]
+async fn video_for_anonymous_users_has_no_position() {
+    use crate::mp4::atom;
+    use warp::hyper::body::to_bytes;
+    let xyz = b"\0\x12\0\0+59.3293+018.0686/";
+    let data = [
+        atom(b"ftyp", b"isom\0\0\0\0isom"),
+        atom(b"moov", &atom(b"udta", &atom(b"\xa9xyz", xyz))),
+        atom(b"mdat", b"frames"),
+    ]
+    .concat();
+    let path = std::env::temp_dir()
+        .join(format!("rphotos-video-test-{}.mp4", std::process::id()));
+    std::fs::write(&path, &data).unwrap();
+    let body = |public, range: Option<&str>| {
+        let range = range.map(String::from);
+        let path = path.clone();
+        async move {
+            let response =
+                stream_video(Builder::new(), path, "video/mp4", range, public)
+                    .await
+                    .unwrap();
+            to_bytes(response.into_body()).await.unwrap()
+        }
+    };
+    let private = body(false, None).await;
+    let public = body(true, None).await;
+    let public_range = body(true, Some("bytes=30-")).await;
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(&private[..], &data[..]);
+    assert_eq!(public.len(), data.len());
+    let text = String::from_utf8_lossy(&public);
+    assert!(!text.contains("59.3293") && !text.contains("udta"));
+    assert!(text.ends_with("mdatframes"));
+    assert_eq!(&public_range[..], &public[30..]);
+}
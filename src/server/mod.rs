@@ -1,6 +1,7 @@
 mod admin;
 mod api;
 mod autocomplete;
+mod conditional;
 mod context;
 mod image;
 mod login;
@@ -8,33 +9,41 @@ mod photolink;
 mod render_ructe;
 pub mod search;
 mod splitlist;
+mod stream;
 mod urlstring;
 mod views_by_category;
 mod views_by_date;
 
+pub use self::admin::{CopyPositionForm, ShiftTimeForm};
+use self::conditional::{not_modified, with_validators, Conditions};
 use self::context::create_session_filter;
 pub use self::context::{Context, ContextFilter};
+pub use self::image::srcset;
 pub use self::photolink::PhotoLink;
 use self::render_ructe::BuilderExt;
 use self::search::*;
 use self::views_by_category::*;
 use self::views_by_date::*;
 use super::{CacheOpt, DbOpt, DirOpt};
+use crate::adm::duplicates::{find_duplicates, DEFAULT_DISTANCE};
 use crate::adm::result::Error;
 use crate::fetch_places::OverpassOpt;
-use crate::models::Photo;
+use crate::metadata::MetadataOpt;
+use crate::models::{Photo, SizeVariant};
 use crate::pidfiles::handle_pid_file;
 use crate::templates::{self, Html, RenderRucte};
+use crate::watermark::WatermarkOpt;
 use chrono::Datelike;
 use diesel::prelude::*;
-use log::info;
+use log::{info, warn};
 use serde::Deserialize;
 use std::net::SocketAddr;
 use structopt::StructOpt;
+use tokio::task::spawn_blocking;
 use warp::filters::path::Tail;
 use warp::http::{header, response::Builder, StatusCode};
 use warp::reply::Response;
-use warp::{self, Filter, Rejection, Reply};
+use warp::{self, Filter, Rejection};
 
 #[derive(StructOpt)]
 #[structopt(rename_all = "kebab-case")]
@@ -47,6 +56,17 @@ pub struct Args {
     photos: DirOpt,
     #[structopt(flatten)]
     overpass: OverpassOpt,
+    #[structopt(flatten)]
+    metadata: MetadataOpt,
+    #[structopt(flatten)]
+    watermark: WatermarkOpt,
+
+    /// Let anyone download the originals of public photos.
+    ///
+    /// Only jpeg originals are served, without position, serial
+    /// numbers and other private metadata.
+    #[structopt(long, env = "RPHOTOS_PUBLIC_ORIGINALS")]
+    public_originals: bool,
 
     /// Write (and read, if --replace) a pid file with the name
     /// given as <PIDFILE>.
@@ -65,6 +85,18 @@ pub struct Args {
     /// Signing key for jwt
     #[structopt(long, env = "JWT_KEY", hide_env_values = true)]
     jwt_key: String,
+    /// Extra sizes of scaled images, like s2=576,m2=2160.
+    ///
+    /// Each size is a name and the max of width and height in pixels.
+    /// Browsers are offered all sizes in srcset, to choose the best
+    /// one for the screen.
+    #[structopt(
+        long,
+        env = "RPHOTOS_SIZE_VARIANTS",
+        default_value = "s2=576,m2=2160",
+        use_delimiter = true
+    )]
+    size_variants: Vec<SizeVariant>,
 }
 
 pub async fn run(args: &Args) -> Result<(), Error> {
@@ -79,6 +111,7 @@ pub async fn run(args: &Args) -> Result<(), Error> {
     let static_routes = path("static")
         .and(get())
         .and(path::tail())
+        .and(conditional::conditions())
         .and_then(static_file);
     #[rustfmt::skip]
     let routes = warp::any()
@@ -88,7 +121,9 @@ pub async fn run(args: &Args) -> Result<(), Error> {
         .or(path("logout").and(end()).and(s()).map(login::logout))
         .or(get().and(end()).and(s()).map(all_years))
         .or(get().and(path("img")).and(param()).and(end()).and(s()).map(photo_details))
-        .or(get().and(path("img")).and(param()).and(end()).and(s()).and_then(image::show_image))
+        .or(get().and(path("img")).and(param()).and(end()).and(warp::header::optional("accept")).and(warp::header::optional("range")).and(conditional::conditions()).and(s()).and_then(image::show_image))
+        .or(get().and(path("img")).and(param()).and(path("raw")).and(end()).and(warp::header::optional("range")).and(conditional::conditions()).and(s()).and_then(image::show_raw))
+        .or(get().and(path("img")).and(param()).and(path("video")).and(end()).and(warp::header::optional("range")).and(s()).and_then(image::show_video))
         .or(get().and(path("0")).and(end()).and(s()).map(all_null_date))
         .or(get().and(param()).and(end()).and(s()).map(months_in_year))
         .or(get().and(param()).and(param()).and(end()).and(s()).map(days_in_month))
@@ -97,6 +132,7 @@ pub async fn run(args: &Args) -> Result<(), Error> {
         .or(path("place").and(place_routes(s())))
         .or(path("tag").and(tag_routes(s())))
         .or(get().and(path("random")).and(end()).and(s()).map(random_image))
+        .or(get().and(path("duplicates")).and(end()).and(s()).and(query()).and_then(duplicates))
         .or(get().and(path("thisday")).and(end()).and(s()).map(on_this_day))
         .or(get().and(path("next")).and(end()).and(s()).and(query()).map(next_image))
         .or(get().and(path("prev")).and(end()).and(s()).and(query()).map(prev_image))
@@ -165,14 +201,26 @@ fn error_response(err: StatusCode) -> Result<Response, Rejection> {
 /// Handler for static files.
 /// Create a response from the file data with a correct content type
 /// and a far expires header (or a 404 if the file does not exist).
-async fn static_file(name: Tail) -> Result<impl Reply, Rejection> {
+///
+/// The static file names contain a hash of the content, so the name
+/// works as an etag.
+async fn static_file(
+    name: Tail,
+    conditions: Conditions,
+) -> Result<Response, Rejection> {
     use templates::statics::StaticFile;
     if let Some(data) = StaticFile::get(name.as_str()) {
-        Ok(Builder::new()
+        let etag = format!("\"{}\"", data.name);
+        let builder =
+            with_validators(Builder::new(), &etag, None).far_expires();
+        if conditions.is_fresh(&etag, None) {
+            return Ok(not_modified(builder));
+        }
+        Ok(builder
             .status(StatusCode::OK)
             .header(header::CONTENT_TYPE, data.mime.as_ref())
-            .far_expires()
-            .body(data.content))
+            .body(data.content.into())
+            .unwrap())
     } else {
         println!("Static file {:?} not found", name);
         Err(warp::reject::not_found())
@@ -196,6 +244,38 @@ fn random_image(context: Context) -> Response {
     }
 }
 
+/// Show groups of similar photos.
+///
+/// The search is cpu-bound, so it runs outside of the async workers.
+async fn duplicates(
+    context: Context,
+    query: DuplicatesQuery,
+) -> Result<Response, Rejection> {
+    if !context.is_authorized() {
+        return permission_denied();
+    }
+    let distance = query.distance.unwrap_or(DEFAULT_DISTANCE).min(64);
+    let found = match context.db() {
+        Ok(db) => spawn_blocking(move || find_duplicates(&db, distance))
+            .await
+            .unwrap_or_else(|e| Err(Error::Other(e.to_string()))),
+        Err(e) => Err(e.into()),
+    };
+    match found {
+        Ok(groups) => Builder::new()
+            .html(|o| templates::duplicates(o, &context, distance, &groups)),
+        Err(err) => {
+            warn!("Failed to find duplicates: {}", err);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DuplicatesQuery {
+    distance: Option<u32>,
+}
+
 fn photo_details(id: i32, context: Context) -> Response {
     use crate::schema::photos::dsl::photos;
     let c = context.db().unwrap();
@@ -224,6 +304,8 @@ fn photo_details(id: i32, context: Context) -> Response {
                         &tphoto.load_position(&c),
                         &tphoto.load_attribution(&c),
                         &tphoto.load_camera(&c),
+                        &tphoto.load_lens(&c),
+                        &tphoto.load_raw(&c),
                         &tphoto,
                     )
                 })
@@ -1,12 +1,16 @@
+use super::image::srcset;
 use super::urlstring::UrlString;
-use crate::models::{Photo, SizeTag};
+use crate::models::{Photo, SizeTag, SizeVariant};
 use chrono::Datelike;
 
 pub struct PhotoLink {
     pub title: Option<String>,
     pub href: String,
     pub id: i32,
+    /// See [`Photo::image_version`].
+    pub version: String,
     pub size: (u32, u32),
+    pub is_video: bool,
     pub lable: Option<String>,
 }
 
@@ -23,9 +27,12 @@ impl PhotoLink {
                 PhotoLink::no_title(&g[0])
             }
         } else {
-            fn imgscore(p: &Photo) -> i16 {
+            fn imgscore(p: &Photo) -> (bool, i16) {
                 // Only score below 19 is worse than ungraded.
-                p.grade.unwrap_or(19) * if p.is_public { 5 } else { 4 }
+                // Videos are only used for groups with no photos.
+                let score =
+                    p.grade.unwrap_or(19) * if p.is_public { 5 } else { 4 };
+                (!p.is_video(), score)
             }
             let photo = g.iter().max_by_key(|p| imgscore(p)).unwrap();
             let (title, lable) = {
@@ -107,7 +114,9 @@ impl PhotoLink {
                 title,
                 href: url.into(),
                 id: photo.id,
+                version: photo.image_version(),
                 size: photo.get_size(SizeTag::Small),
+                is_video: photo.is_video(),
                 lable: Some(lable),
             }
         }
@@ -117,7 +126,9 @@ impl PhotoLink {
             title: p.date.map(|d| d.format("%F").to_string()),
             href: format!("/img/{}", p.id),
             id: p.id,
+            version: p.image_version(),
             size: p.get_size(SizeTag::Small),
+            is_video: p.is_video(),
             lable: p.date.map(|d| d.format("%T").to_string()),
         }
     }
@@ -126,11 +137,22 @@ impl PhotoLink {
             title: None, // p.date.map(|d| d.format("%F").to_string()),
             href: format!("/img/{}", p.id),
             id: p.id,
+            version: p.image_version(),
             size: p.get_size(SizeTag::Small),
+            is_video: p.is_video(),
             lable: p.date.map(|d| d.format("%T").to_string()),
         }
     }
     pub fn is_portrait(&self) -> bool {
         self.size.1 > self.size.0
     }
+    pub fn srcset(&self, variants: &[SizeVariant]) -> String {
+        srcset(
+            self.id,
+            &self.version,
+            SizeTag::Small,
+            self.size.0,
+            variants,
+        )
+    }
 }
@@ -2,7 +2,7 @@ use super::splitlist::{get_positions, split_to_group_links};
 use super::urlstring::UrlString;
 use super::{Context, RenderRucte};
 use crate::adm::result::Error;
-use crate::models::{Facet, Person, Photo, Place, Tag};
+use crate::models::{Facet, Lens, Person, Photo, Place, Tag};
 use crate::schema::photo_people::dsl as pp;
 use crate::schema::photo_places::dsl as pl;
 use crate::schema::photo_tags::dsl as pt;
@@ -16,7 +16,8 @@ use warp::http::response::Builder;
 use warp::reply::Response;
 
 pub fn search(context: Context, query: Vec<(String, String)>) -> Response {
-    let query = SearchQuery::load(query, &context.db().unwrap()).unwrap();
+    let c = context.db().unwrap();
+    let query = SearchQuery::load(query, &c).unwrap();
 
     let mut photos = Photo::query(context.is_authorized());
     if let Some(since) = query.since.as_ref() {
@@ -55,6 +56,15 @@ pub fn search(context: Context, query: Vec<(String, String)>) -> Response {
             photos.filter(p::id.ne_all(ids))
         }
     }
+    if let Some(lens) = &query.lens {
+        photos = photos.filter(p::lens_id.eq(lens.id));
+    }
+    if let Some(min) = query.focal_min {
+        photos = photos.filter(p::focal_length.ge(min));
+    }
+    if let Some(max) = query.focal_max {
+        photos = photos.filter(p::focal_length.le(max));
+    }
     if let Some(pos) = query.pos {
         use crate::schema::positions::dsl as pos;
         let pos_ids = pos::positions.select(pos::photo_id);
@@ -65,7 +75,6 @@ pub fn search(context: Context, query: Vec<(String, String)>) -> Response {
         }
     }
 
-    let c = context.db().unwrap();
     let photos = photos
         .order((p::date.desc().nulls_last(), p::id.desc()))
         .load(&c)
@@ -74,9 +83,12 @@ pub fn search(context: Context, query: Vec<(String, String)>) -> Response {
     let n = photos.len();
     let coords = get_positions(&photos, &c);
     let links = split_to_group_links(&photos, &query.to_base_url(), true);
+    let lenses = Lens::all(&c).unwrap();
 
     Builder::new()
-        .html(|o| templates::search(o, &context, &query, n, &links, &coords))
+        .html(|o| {
+            templates::search(o, &context, &query, &lenses, n, &links, &coords)
+        })
         .unwrap()
 }
 
@@ -91,6 +103,10 @@ pub struct SearchQuery {
     pub since: QueryDateTime,
     pub until: QueryDateTime,
     pub pos: Option<bool>,
+    pub lens: Option<Lens>,
+    /// Focal length range, in mm.
+    pub focal_min: Option<f32>,
+    pub focal_max: Option<f32>,
     /// Query (free-text, don't know what to do)
     pub q: String,
 }
@@ -171,6 +187,21 @@ impl SearchQuery {
                         }
                     }
                 }
+                "lens" if !val.is_empty() => {
+                    result.lens = val
+                        .parse()
+                        .ok()
+                        .and_then(|id| Lens::by_id(id, db).ok());
+                    if result.lens.is_none() {
+                        warn!("No lens {:?}", val);
+                    }
+                }
+                "fl_min" if !val.is_empty() => {
+                    result.focal_min = parse_focal(&val);
+                }
+                "fl_max" if !val.is_empty() => {
+                    result.focal_max = parse_focal(&val);
+                }
                 "from" => {
                     result.since = QueryDateTime::from_img(val.parse()?, db)?;
                 }
@@ -196,10 +227,27 @@ impl SearchQuery {
         for i in &self.pos {
             result.cond_query("pos", *i, "t");
         }
+        if let Some(lens) = &self.lens {
+            result.query("lens", lens.id);
+        }
+        if let Some(min) = self.focal_min {
+            result.query("fl_min", min);
+        }
+        if let Some(max) = self.focal_max {
+            result.query("fl_max", max);
+        }
         result
     }
 }
 
+fn parse_focal(val: &str) -> Option<f32> {
+    let focal = val.parse().ok().filter(|f: &f32| f.is_finite());
+    if focal.is_none() {
+        warn!("Bad focal length {:?}", val);
+    }
+    focal
+}
+
 #[derive(Debug, Default)]
 pub struct QueryDateTime {
     val: Option<NaiveDateTime>,
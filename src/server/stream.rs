@@ -0,0 +1,219 @@
+//! Stream files from disk, with support for byte range requests.
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt};
+use warp::http::response::Builder;
+use warp::http::{header, StatusCode};
+use warp::hyper::body::{Body, Bytes};
+use warp::reply::Response;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Respond with (a range of) the file at `path`.
+///
+/// `builder` may have headers set already, like expires and etag.
+/// `range` is the value of a Range request header, if any.  Only
+/// single ranges are supported, a request for several ranges gets
+/// the entire file.
+pub async fn stream_file(
+    builder: Builder,
+    path: &Path,
+    content_type: &str,
+    range: Option<String>,
+) -> io::Result<Response> {
+    stream_patched_file(builder, path, content_type, range, |_, _| ()).await
+}
+
+/// Respond with (a range of) the file at `path`, changed by `patch`.
+///
+/// Each chunk read from the file is given to `patch` with its
+/// position in the file before it is sent.  The patch must not
+/// change the length, as that is sent before the content.
+pub async fn stream_patched_file(
+    builder: Builder,
+    path: &Path,
+    content_type: &str,
+    range: Option<String>,
+    mut patch: impl FnMut(u64, &mut [u8]) + Send + 'static,
+) -> io::Result<Response> {
+    let mut file = File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let builder = builder
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "bytes");
+    let (builder, start, end) = match range.map(|r| parse_range(&r, len)) {
+        Some(Range::Satisfiable(start, end)) => (
+            builder.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, len),
+            ),
+            start,
+            end,
+        ),
+        Some(Range::Unsatisfiable) => {
+            return Ok(Builder::new()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty())
+                .unwrap());
+        }
+        Some(Range::Ignored) | None => {
+            (builder.status(StatusCode::OK), 0, len)
+        }
+    };
+    file.seek(SeekFrom::Start(start)).await?;
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut left = end - start;
+        let mut buf = vec![0; CHUNK_SIZE];
+        while left > 0 {
+            let want = buf.len().min(left as usize);
+            let n = match file.read(&mut buf[..want]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            patch(end - left, &mut buf[..n]);
+            if sender
+                .send_data(Bytes::copy_from_slice(&buf[..n]))
+                .await
+                .is_err()
+            {
+                // The client went away.
+                break;
+            }
+            left -= n as u64;
+        }
+        if left > 0 {
+            sender.abort();
+        }
+    });
+    Ok(builder
+        .header(header::CONTENT_LENGTH, end - start)
+        .body(body)
+        .unwrap())
+}
+
+/// A Content-Disposition value, `kind` inline or attachment, with the
+/// file name of `path`.
+///
+/// A name that is not plain ascii is given both with the odd chars
+/// replaced and percent-encoded as in RFC 6266.
+pub fn content_disposition(kind: &str, path: &Path) -> String {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("image");
+    let plain = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c == ' ' || c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    if plain == name {
+        return format!("{}; filename=\"{}\"", kind, name);
+    }
+    let encoded = name
+        .bytes()
+        .map(|b| match b {
+            b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'-' | b'.' | b'_' => {
+                char::from(b).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect::<String>();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        kind, plain, encoded,
+    )
+}
+
+#[test]
+fn disposition_plain() {
+    assert_eq!(
+        content_disposition("inline", Path::new("2021/IMG_1234.JPG")),
+        "inline; filename=\"IMG_1234.JPG\"",
+    );
+}
+
+#[test]
+fn disposition_unicode() {
+    assert_eq!(
+        content_disposition("attachment", Path::new("a/Måne \"ö\".jpg")),
+        "attachment; filename=\"M_ne ___.jpg\"; \
+         filename*=UTF-8''M%C3%A5ne%20%22%C3%B6%22.jpg",
+    );
+}
+
+#[derive(Debug, PartialEq)]
+enum Range {
+    /// From start (inclusive) to end (exclusive).
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+    /// Not understood or not supported, send the entire file.
+    Ignored,
+}
+
+/// Parse a Range header value for a file of `len` bytes.
+fn parse_range(range: &str, len: u64) -> Range {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Range::Ignored,
+    };
+    let (start, end) = match spec.find('-') {
+        Some(pos) => (spec[..pos].trim(), spec[pos + 1..].trim()),
+        None => return Range::Ignored,
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end + 1),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len)
+        }
+        _ => return Range::Ignored,
+    };
+    if start < len {
+        Range::Satisfiable(start, end.min(len))
+    } else {
+        Range::Unsatisfiable
+    }
+}
+
+#[test]
+fn range_from_to() {
+    assert_eq!(parse_range("bytes=0-499", 1000), Range::Satisfiable(0, 500));
+}
+
+#[test]
+fn range_open_end() {
+    assert_eq!(
+        parse_range("bytes=900-", 1000),
+        Range::Satisfiable(900, 1000)
+    );
+}
+
+#[test]
+fn range_suffix() {
+    assert_eq!(
+        parse_range("bytes=-100", 1000),
+        Range::Satisfiable(900, 1000)
+    );
+}
+
+#[test]
+fn range_past_end() {
+    assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+    assert_eq!(
+        parse_range("bytes=10-5000", 1000),
+        Range::Satisfiable(10, 1000)
+    );
+}
+
+#[test]
+fn range_unsupported() {
+    assert_eq!(parse_range("bytes=0-1,5-6", 1000), Range::Ignored);
+    assert_eq!(parse_range("lines=1-2", 1000), Range::Ignored);
+    assert_eq!(parse_range("bytes=5-1", 1000), Range::Ignored);
+}
@@ -43,7 +43,9 @@ pub fn all_years(context: Context) -> Response {
                 href: format!("/{}/", year.unwrap_or(0)),
                 lable: Some(format!("{} images", count)),
                 id: photo.id,
+                version: photo.image_version(),
                 size: photo.get_size(SizeTag::Small),
+                is_video: photo.is_video(),
             }
         })
         .collect::<Vec<_>>();
@@ -90,7 +92,9 @@ pub fn months_in_year(year: i32, context: Context) -> Response {
                 href: format!("/{}/{}/", year, month),
                 lable: Some(format!("{} pictures", count)),
                 id: photo.id,
+                version: photo.image_version(),
                 size: photo.get_size(SizeTag::Small),
+                is_video: photo.is_video(),
             }
         })
         .collect::<Vec<_>>();
@@ -165,7 +169,9 @@ pub fn days_in_month(year: i32, month: u32, context: Context) -> Response {
                 href: format!("/{}/{}/{}", year, month, day),
                 lable: Some(format!("{} pictures", count)),
                 id: photo.id,
+                version: photo.image_version(),
                 size: photo.get_size(SizeTag::Small),
+                is_video: photo.is_video(),
             }
         })
         .collect::<Vec<_>>();
@@ -326,7 +332,9 @@ pub fn on_this_day(context: Context) -> Response {
                             href: format!("/{}/{}/{}", year, month, day),
                             lable: Some(format!("{} pictures", count)),
                             id: photo.id,
+                            version: photo.image_version(),
                             size: photo.get_size(SizeTag::Small),
+                            is_video: photo.is_video(),
                         }
                     })
                     .collect::<Vec<_>>(),
@@ -0,0 +1,415 @@
+//! Watermarks on scaled images shown to anonymous users.
+//!
+//! The watermark is an image file, or the attribution of the photo
+//! written in a configured font.
+use crate::adm::result::Error;
+use ab_glyph::{point, Font, FontArc, PxScale, ScaleFont};
+use image::{imageops, Rgb, RgbImage, Rgba, RgbaImage};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct WatermarkOpt {
+    /// Watermark scaled public images, for anonymous users.
+    ///
+    /// The value is either "attribution", for the attribution of
+    /// each photo, or the path of an image file, preferably a png
+    /// with transparency.  The cache keys of marked images depend on
+    /// the watermark options and files, so changing them does not
+    /// give old marks.
+    #[structopt(long, env = "RPHOTOS_WATERMARK")]
+    watermark: Option<Source>,
+
+    /// A TrueType or OpenType font file for the attribution watermark.
+    #[structopt(
+        long,
+        env = "RPHOTOS_WATERMARK_FONT",
+        required_if("watermark", "attribution")
+    )]
+    watermark_font: Option<PathBuf>,
+
+    /// Corner for the watermark: top-left, top-right, bottom-left or
+    /// bottom-right.
+    #[structopt(
+        long,
+        env = "RPHOTOS_WATERMARK_CORNER",
+        default_value = "bottom-right"
+    )]
+    watermark_corner: Corner,
+
+    /// Opacity of the watermark, from 0 to 1.
+    #[structopt(
+        long,
+        env = "RPHOTOS_WATERMARK_OPACITY",
+        default_value = "0.5"
+    )]
+    watermark_opacity: f32,
+
+    /// Images scaled to less than this many pixels are not marked.
+    #[structopt(
+        long,
+        env = "RPHOTOS_WATERMARK_MIN_SIZE",
+        default_value = "500"
+    )]
+    watermark_min_size: u32,
+}
+
+impl WatermarkOpt {
+    /// Get the configured watermark, if any, reading its image or
+    /// font file.
+    pub fn load(&self) -> Result<Option<Watermark>, Error> {
+        let mut config = Sha256::new();
+        let kind = match &self.watermark {
+            None => return Ok(None),
+            Some(Source::Attribution) => {
+                let path = self.watermark_font.as_ref().ok_or_else(|| {
+                    Error::Other("An attribution needs a font".into())
+                })?;
+                let data =
+                    fs::read(path).map_err(|e| Error::in_file(&e, path))?;
+                config.update(b"attribution");
+                config.update(&data);
+                Kind::Attribution(
+                    FontArc::try_from_vec(data)
+                        .map_err(|e| Error::in_file(&e, path))?,
+                )
+            }
+            Some(Source::Image(path)) => {
+                let data =
+                    fs::read(path).map_err(|e| Error::in_file(&e, path))?;
+                config.update(b"image");
+                config.update(&data);
+                Kind::Image(Arc::new(
+                    image::load_from_memory(&data)
+                        .map_err(|e| Error::in_file(&e, path))?
+                        .to_rgba8(),
+                ))
+            }
+        };
+        let opacity = self.watermark_opacity.clamp(0., 1.);
+        config.update(format!(
+            "{:?} {} {}",
+            self.watermark_corner, opacity, self.watermark_min_size,
+        ));
+        Ok(Some(Watermark {
+            kind,
+            corner: self.watermark_corner,
+            opacity,
+            min_size: self.watermark_min_size,
+            config,
+        }))
+    }
+}
+
+enum Source {
+    Attribution,
+    Image(PathBuf),
+}
+
+impl FromStr for Source {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "attribution" => Source::Attribution,
+            path => Source::Image(path.into()),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl FromStr for Corner {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top-left" => Ok(Corner::TopLeft),
+            "top-right" => Ok(Corner::TopRight),
+            "bottom-left" => Ok(Corner::BottomLeft),
+            "bottom-right" => Ok(Corner::BottomRight),
+            _ => Err(format!("Unknown corner {:?}", s)),
+        }
+    }
+}
+
+/// A configured watermark.
+#[derive(Clone)]
+pub struct Watermark {
+    kind: Kind,
+    corner: Corner,
+    opacity: f32,
+    min_size: u32,
+    /// Hash of the file and options, for the keys of the marks.
+    config: Sha256,
+}
+
+#[derive(Clone)]
+enum Kind {
+    Attribution(FontArc),
+    Image(Arc<RgbaImage>),
+}
+
+impl Watermark {
+    /// The mark for an image of a photo scaled to `px`, if any.
+    ///
+    /// Small images are not marked, and neither are images without
+    /// an attribution when that is the watermark.  The `attribution`
+    /// is only called when needed.
+    pub fn mark(
+        &self,
+        px: u32,
+        attribution: impl FnOnce() -> Option<String>,
+    ) -> Option<Mark> {
+        if px < self.min_size {
+            return None;
+        }
+        let mut hash = self.config.clone();
+        let image = match &self.kind {
+            Kind::Attribution(font) => {
+                let text = format!("© {}", attribution()?);
+                hash.update(&text);
+                MarkImage::Text(text, font.clone())
+            }
+            Kind::Image(image) => MarkImage::Image(image.clone()),
+        };
+        Some(Mark {
+            image,
+            corner: self.corner,
+            opacity: self.opacity,
+            key: format!("{:x}", hash.finalize())[..12].to_string(),
+        })
+    }
+}
+
+/// A watermark to put on a specific image.
+#[derive(Clone)]
+pub struct Mark {
+    image: MarkImage,
+    corner: Corner,
+    opacity: f32,
+    key: String,
+}
+
+#[derive(Clone)]
+enum MarkImage {
+    Text(String, FontArc),
+    Image(Arc<RgbaImage>),
+}
+
+impl Mark {
+    /// A short hash of the watermark configuration and the text of
+    /// this mark, that differs for marks that look different.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Draw this mark on `img`.
+    ///
+    /// An image mark is scaled to a fifth of the width of `img`, text
+    /// is drawn in white with a dark shadow, in a size relative to
+    /// `img`.
+    pub fn apply(&self, img: &mut RgbImage) {
+        let short_side = img.width().min(img.height());
+        let mark = match &self.image {
+            MarkImage::Text(text, font) => {
+                render_text(text, font, (short_side / 25).max(10) as f32)
+            }
+            MarkImage::Image(mark) => {
+                let width = (img.width() / 5).max(1);
+                let height = (u64::from(mark.height()) * u64::from(width)
+                    / u64::from(mark.width().max(1)))
+                .max(1) as u32;
+                imageops::resize(
+                    mark.as_ref(),
+                    width,
+                    height,
+                    imageops::FilterType::Triangle,
+                )
+            }
+        };
+        let margin = short_side / 50;
+        let left = match self.corner {
+            Corner::TopLeft | Corner::BottomLeft => margin,
+            _ => img.width().saturating_sub(mark.width() + margin),
+        };
+        let top = match self.corner {
+            Corner::TopLeft | Corner::TopRight => margin,
+            _ => img.height().saturating_sub(mark.height() + margin),
+        };
+        blend(img, &mark, left, top, self.opacity);
+    }
+}
+
+/// Blend `mark` onto `img` at (`left`, `top`).
+fn blend(
+    img: &mut RgbImage,
+    mark: &RgbaImage,
+    left: u32,
+    top: u32,
+    opacity: f32,
+) {
+    for (x, y, Rgba([r, g, b, a])) in mark.enumerate_pixels() {
+        let (x, y) = (left + x, top + y);
+        if x >= img.width() || y >= img.height() {
+            continue;
+        }
+        let alpha = f32::from(*a) / 255. * opacity;
+        let Rgb(pixel) = img.get_pixel_mut(x, y);
+        for (c, m) in pixel.iter_mut().zip(&[*r, *g, *b]) {
+            let mixed = f32::from(*c) * (1. - alpha) + f32::from(*m) * alpha;
+            *c = mixed.round() as u8;
+        }
+    }
+}
+
+/// Render `text` white, with a dark shadow, `px` pixels high.
+fn render_text(text: &str, font: &FontArc, px: f32) -> RgbaImage {
+    let font = font.as_scaled(PxScale::from(px));
+    let shadow = (px / 16.).ceil();
+    let mut glyphs = Vec::new();
+    let mut x = 0.;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            x += font.kern(previous, id);
+        }
+        glyphs.push(id.with_scale_and_position(px, point(x, font.ascent())));
+        x += font.h_advance(id);
+        previous = Some(id);
+    }
+    let width = (x + shadow).ceil() as u32;
+    let height = (font.height() + shadow).ceil() as u32;
+    let mut result = RgbaImage::new(width, height);
+    for (offset, color) in &[(shadow, [0, 0, 0, 160]), (0., [255; 4])] {
+        for glyph in &glyphs {
+            let mut glyph = glyph.clone();
+            glyph.position.x += offset;
+            glyph.position.y += offset;
+            let outline = match font.outline_glyph(glyph) {
+                Some(outline) => outline,
+                None => continue, // e.g. a space
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|x, y, coverage| {
+                let x = bounds.min.x as i64 + i64::from(x);
+                let y = bounds.min.y as i64 + i64::from(y);
+                if x >= 0 && y >= 0 && x < width.into() && y < height.into() {
+                    let pixel = result.get_pixel_mut(x as u32, y as u32);
+                    paint(pixel, *color, coverage);
+                }
+            });
+        }
+    }
+    result
+}
+
+/// Paint `color` over `pixel`, with `coverage` of its alpha.
+fn paint(pixel: &mut Rgba<u8>, color: [u8; 4], coverage: f32) {
+    let Rgba(below) = *pixel;
+    let alpha = f32::from(color[3]) / 255. * coverage.clamp(0., 1.);
+    let below_alpha = f32::from(below[3]) / 255. * (1. - alpha);
+    let out_alpha = alpha + below_alpha;
+    if out_alpha <= 0. {
+        return;
+    }
+    for i in 0..3 {
+        let mixed = (f32::from(color[i]) * alpha
+            + f32::from(below[i]) * below_alpha)
+            / out_alpha;
+        pixel.0[i] = mixed.round() as u8;
+    }
+    pixel.0[3] = (out_alpha * 255.).round() as u8;
+}
+
+#[test]
+#[ignore = "needs /usr/share/fonts/truetype/dejavu/DejaVuSans.ttf"]
+fn text_is_white_with_shadow() {
+    // Any font will do, but none is distributed with rphotos.
+    let path = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+    let font = FontArc::try_from_vec(fs::read(path).unwrap()).unwrap();
+    let text = render_text("© Åsa", &font, 32.);
+    assert_eq!(text.height(), 34);
+    let short = render_text("Åsa", &font, 32.);
+    assert!(short.width() < text.width());
+    assert!(text.pixels().any(|p| p == &Rgba([255; 4])));
+    assert!(text.pixels().any(|p| p == &Rgba([0, 0, 0, 160])));
+    assert!(text.pixels().any(|p| p == &Rgba([0; 4])));
+
+    let watermark = Watermark {
+        kind: Kind::Attribution(font),
+        corner: Corner::BottomRight,
+        opacity: 0.5,
+        min_size: 100,
+        config: Sha256::new(),
+    };
+    let key = |name: &str| {
+        let name = Some(name.to_string());
+        watermark.mark(100, || name).unwrap().key
+    };
+    assert_eq!(key("Åsa"), key("Åsa"));
+    assert_ne!(key("Åsa"), key("Bo"));
+}
+
+#[test]
+fn mark_corner() {
+    let watermark = Watermark {
+        kind: Kind::Image(Arc::new(RgbaImage::from_pixel(
+            10,
+            5,
+            Rgba([255, 255, 255, 255]),
+        ))),
+        corner: Corner::BottomRight,
+        opacity: 0.5,
+        min_size: 100,
+        config: Sha256::new(),
+    };
+    assert!(watermark.mark(99, || None).is_none());
+    let mut img = RgbImage::new(100, 50);
+    watermark.mark(100, || None).unwrap().apply(&mut img);
+    // Scaled to 20x10, one pixel from the edges.
+    assert_eq!(img.get_pixel(98, 48), &Rgb([128; 3]));
+    assert_eq!(img.get_pixel(79, 39), &Rgb([128; 3]));
+    assert_eq!(img.get_pixel(78, 39), &Rgb([0; 3]));
+    assert_eq!(img.get_pixel(99, 49), &Rgb([0; 3]));
+    assert_eq!(img.get_pixel(98, 38), &Rgb([0; 3]));
+}
+
+#[test]
+fn mark_key_depends_on_config() {
+    let path = std::env::temp_dir()
+        .join(format!("rphotos-watermark-test-{}.png", std::process::id()));
+    RgbaImage::from_pixel(10, 5, Rgba([255; 4]))
+        .save(&path)
+        .unwrap();
+    let key = |args: &[&str]| {
+        let path = path.to_str().unwrap();
+        let base = ["rphotos", "--watermark", path];
+        let opt = WatermarkOpt::from_iter(base.iter().chain(args));
+        let watermark = opt.load().unwrap();
+        watermark.unwrap().mark(500, || None).unwrap().key
+    };
+    let plain = key(&[]);
+    let opaque = key(&["--watermark-opacity", "1"]);
+    let corner = key(&["--watermark-corner", "top-left"]);
+    RgbaImage::from_pixel(10, 5, Rgba([0; 4]))
+        .save(&path)
+        .unwrap();
+    let other_image = key(&[]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(plain.len(), 12);
+    assert_ne!(plain, opaque);
+    assert_ne!(plain, corner);
+    assert_ne!(plain, other_image);
+}
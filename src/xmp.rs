@@ -0,0 +1,616 @@
+//! Read and write the xmp metadata I care about.
+//!
+//! Xmp is read from sidecars and jpeg files, and written as sidecars
+//! and embedded in scaled images.
+use crate::adm::result::Error;
+use log::{debug, warn};
+use roxmltree::{Document, Node};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+const DC: &str = "http://purl.org/dc/elements/1.1/";
+const EXIF: &str = "http://ns.adobe.com/exif/1.0/";
+const IPTC_CORE: &str = "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/";
+const IPTC_EXT: &str = "http://iptc.org/std/Iptc4xmpExt/2008-02-29/";
+const PHOTOSHOP: &str = "http://ns.adobe.com/photoshop/1.0/";
+const RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const XMP: &str = "http://ns.adobe.com/xap/1.0/";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct XmpData {
+    /// Keywords, from `dc:subject`.
+    pub keywords: Vec<String>,
+    /// Names of people, from `Iptc4xmpExt:PersonInImage`.
+    pub people: Vec<String>,
+    /// Star rating, -1 for rejected and 0 for unrated.
+    pub rating: Option<i8>,
+    /// Latitude and longitude in degrees, from `exif:GPSLatitude`
+    /// and `exif:GPSLongitude`.
+    pub position: Option<(f64, f64)>,
+    /// The most specific place name, from `Iptc4xmpCore:Location`.
+    pub location: Option<String>,
+    pub city: Option<String>,
+    pub state: Option<String>,
+    pub country: Option<String>,
+    /// Who made the photo, from `dc:creator`.
+    pub creator: Option<String>,
+    /// Copyright notice, from `dc:rights`.
+    pub rights: Option<String>,
+    /// When the photo was taken, from `photoshop:DateCreated`.
+    ///
+    /// An xmp date, like 2021-05-16T14:30:00+02:00.  The offset may
+    /// be missing when it is not known.
+    pub date_created: Option<String>,
+    /// From `dc:description`.
+    pub description: Option<String>,
+}
+
+impl XmpData {
+    /// Read xmp for the image file `path`.
+    ///
+    /// Data is read from a sidecar file (either `IMG_4711.JPG.xmp` or
+    /// `IMG_4711.xmp`) and from xmp embedded in a jpeg file.
+    /// The sidecar rating has priority over the embedded one.
+    /// Returns None if no xmp data is found.
+    pub fn read_for(path: &Path) -> Result<Option<XmpData>, Error> {
+        let mut result = None;
+        if let Some(sidecar) = sidecar_path(path) {
+            debug!("Reading xmp sidecar {:?}", sidecar);
+            let xml = fs::read_to_string(&sidecar)
+                .map_err(|e| Error::in_file(&e, &sidecar))?;
+            result = Some(Self::parse(&xml, &sidecar)?);
+        }
+        if let Some(xml) = embedded_xmp(path)? {
+            let embedded = Self::parse(&xml, path)?;
+            result = Some(match result {
+                Some(sidecar) => sidecar.merge(embedded),
+                None => embedded,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Parse an xmp packet.  The `path` is only used for messages.
+    pub fn parse(xml: &str, path: &Path) -> Result<XmpData, Error> {
+        let doc =
+            Document::parse(xml).map_err(|e| Error::in_file(&e, path))?;
+        let mut result = XmpData::default();
+        let (mut lat, mut long) = (None, None);
+        // Simple properties may be attributes or elements.
+        let mut property = |ns: Option<&str>, name: &str, value: &str| match (
+            ns.unwrap_or_default(),
+            name,
+        ) {
+            (XMP, "Rating") => result.rating = parse_rating(value, path),
+            (EXIF, "GPSLatitude") => lat = parse_gps(value, 'S', path),
+            (EXIF, "GPSLongitude") => long = parse_gps(value, 'W', path),
+            (IPTC_CORE, "Location") => result.location = text(value),
+            (PHOTOSHOP, "City") => result.city = text(value),
+            (PHOTOSHOP, "State") => result.state = text(value),
+            (PHOTOSHOP, "Country") => result.country = text(value),
+            (PHOTOSHOP, "DateCreated") => result.date_created = text(value),
+            _ => (),
+        };
+        let mut keywords = Vec::new();
+        let mut people = Vec::new();
+        let mut creators = Vec::new();
+        let mut rights = Vec::new();
+        let mut descriptions = Vec::new();
+        for node in doc.descendants().filter(Node::is_element) {
+            if node.has_tag_name((RDF, "Description")) {
+                for attr in node.attributes() {
+                    property(attr.namespace(), attr.name(), attr.value());
+                }
+            } else if node.has_tag_name((DC, "subject")) {
+                keywords.extend(list_items(node));
+            } else if node.has_tag_name((DC, "creator")) {
+                creators.extend(list_items(node));
+            } else if node.has_tag_name((DC, "rights")) {
+                rights.extend(list_items(node));
+            } else if node.has_tag_name((DC, "description")) {
+                descriptions.extend(list_items(node));
+            } else if node.has_tag_name((IPTC_EXT, "PersonInImage")) {
+                people.extend(list_items(node));
+            } else if let Some(value) = node.text() {
+                let tag = node.tag_name();
+                property(tag.namespace(), tag.name(), value);
+            }
+        }
+        result.keywords = keywords;
+        result.people = people;
+        if !creators.is_empty() {
+            result.creator = Some(creators.join(", "));
+        }
+        // Language alternatives, use the first (default) one.
+        result.rights = rights.into_iter().next();
+        result.description = descriptions.into_iter().next();
+        if let (Some(lat), Some(long)) = (lat, long) {
+            result.position = Some((lat, long));
+        }
+        Ok(result)
+    }
+
+    /// Write this data as an xmp sidecar document.
+    pub fn to_xml(&self) -> String {
+        let mut attrs = String::new();
+        let mut attr = |name: &str, value: &str| {
+            attrs.push_str(&format!("\n   {}=\"{}\"", name, escape(value)));
+        };
+        if let Some(rating) = self.rating {
+            attr("xmp:Rating", &rating.to_string());
+        }
+        if let Some((lat, long)) = self.position {
+            attr("exif:GPSVersionID", "2.2.0.0");
+            attr("exif:GPSLatitude", &format_gps(lat, 'N', 'S'));
+            attr("exif:GPSLongitude", &format_gps(long, 'E', 'W'));
+        }
+        for (name, value) in &[
+            ("Iptc4xmpCore:Location", &self.location),
+            ("photoshop:City", &self.city),
+            ("photoshop:State", &self.state),
+            ("photoshop:Country", &self.country),
+            ("photoshop:DateCreated", &self.date_created),
+        ] {
+            if let Some(value) = value {
+                attr(name, value);
+            }
+        }
+        let mut bags = String::new();
+        for (name, items) in &[
+            ("dc:subject", &self.keywords),
+            ("Iptc4xmpExt:PersonInImage", &self.people),
+        ] {
+            if !items.is_empty() {
+                bags.push_str(&format!("   <{}>\n    <rdf:Bag>\n", name));
+                for item in items.iter() {
+                    bags.push_str(&format!(
+                        "     <rdf:li>{}</rdf:li>\n",
+                        escape(item),
+                    ));
+                }
+                bags.push_str(&format!("    </rdf:Bag>\n   </{}>\n", name));
+            }
+        }
+        if let Some(creator) = &self.creator {
+            bags.push_str(&format!(
+                "   <dc:creator>\n    <rdf:Seq>\n     <rdf:li>{}</rdf:li>\n    \
+                 </rdf:Seq>\n   </dc:creator>\n",
+                escape(creator),
+            ));
+        }
+        for (name, value) in &[
+            ("dc:rights", &self.rights),
+            ("dc:description", &self.description),
+        ] {
+            if let Some(value) = value {
+                bags.push_str(&format!(
+                    "   <{name}>\n    <rdf:Alt>\n     \
+                     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n    \
+                     </rdf:Alt>\n   </{name}>\n",
+                    escape(value),
+                    name = name,
+                ));
+            }
+        }
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <x:xmpmeta xmlns:x=\"adobe:ns:meta/\" x:xmptk=\"rphotos\">\n\
+             \x20<rdf:RDF xmlns:rdf=\"{rdf}\">\n\
+             \x20 <rdf:Description rdf:about=\"\"\n\
+             \x20   xmlns:dc=\"{dc}\"\n\
+             \x20   xmlns:exif=\"{exif}\"\n\
+             \x20   xmlns:Iptc4xmpCore=\"{iptc_core}\"\n\
+             \x20   xmlns:Iptc4xmpExt=\"{iptc_ext}\"\n\
+             \x20   xmlns:photoshop=\"{photoshop}\"\n\
+             \x20   xmlns:xmp=\"{xmp}\"{attrs}>\n\
+             {bags}\
+             \x20 </rdf:Description>\n\
+             \x20</rdf:RDF>\n\
+             </x:xmpmeta>\n",
+            rdf = RDF,
+            dc = DC,
+            exif = EXIF,
+            iptc_core = IPTC_CORE,
+            iptc_ext = IPTC_EXT,
+            photoshop = PHOTOSHOP,
+            xmp = XMP,
+            attrs = attrs,
+            bags = bags,
+        )
+    }
+
+    /// The rating as a grade from 0 to 100, if rated.
+    pub fn grade(&self) -> Option<i16> {
+        match self.rating? {
+            r if r < 0 => Some(0),
+            0 => None,
+            r => Some(i16::from(r.min(5)) * 20),
+        }
+    }
+
+    /// Set the rating from a grade from 0 to 100.
+    ///
+    /// This is the inverse of [`XmpData::grade`], with grades rounded
+    /// to the nearest star.
+    pub fn set_grade(&mut self, grade: Option<i16>) {
+        self.rating = grade.map(|grade| match grade {
+            g if g <= 0 => -1,
+            g => ((g + 10) / 20).clamp(1, 5) as i8,
+        });
+    }
+
+    fn merge(mut self, other: XmpData) -> XmpData {
+        for keyword in other.keywords {
+            if !self.keywords.contains(&keyword) {
+                self.keywords.push(keyword);
+            }
+        }
+        for person in other.people {
+            if !self.people.contains(&person) {
+                self.people.push(person);
+            }
+        }
+        self.rating = self.rating.or(other.rating);
+        self.position = self.position.or(other.position);
+        self.location = self.location.or(other.location);
+        self.city = self.city.or(other.city);
+        self.state = self.state.or(other.state);
+        self.country = self.country.or(other.country);
+        self.creator = self.creator.or(other.creator);
+        self.rights = self.rights.or(other.rights);
+        self.date_created = self.date_created.or(other.date_created);
+        self.description = self.description.or(other.description);
+        self
+    }
+}
+
+/// Find an existing sidecar file for the image file `path`.
+pub fn sidecar_path(path: &Path) -> Option<PathBuf> {
+    let mut full = path.as_os_str().to_owned();
+    full.push(".xmp");
+    vec![
+        PathBuf::from(full),
+        path.with_extension("xmp"),
+        path.with_extension("XMP"),
+    ]
+    .into_iter()
+    .find(|sidecar| sidecar.is_file())
+}
+
+/// Embed the xmp packet `xml` in `jpeg`.
+///
+/// The packet is put in an APP1 segment after any JFIF APP0 segment,
+/// which must be first.  A packet too large for a segment is not
+/// embedded.
+pub fn embed_in_jpeg(jpeg: Vec<u8>, xml: &str) -> Vec<u8> {
+    const HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+    let len = 2 + HEADER.len() + xml.len();
+    if !jpeg.starts_with(&[0xFF, 0xD8]) || len > usize::from(u16::MAX) {
+        warn!("Can not embed xmp of {} bytes", xml.len());
+        return jpeg;
+    }
+    let pos = if jpeg[2..].starts_with(&[0xFF, 0xE0]) && jpeg.len() > 6 {
+        4 + usize::from(u16::from_be_bytes([jpeg[4], jpeg[5]]))
+    } else {
+        2
+    };
+    let mut result = Vec::with_capacity(jpeg.len() + 2 + len);
+    result.extend_from_slice(&jpeg[..pos]);
+    result.extend_from_slice(&[0xFF, 0xE1]);
+    result.extend_from_slice(&(len as u16).to_be_bytes());
+    result.extend_from_slice(HEADER);
+    result.extend_from_slice(xml.as_bytes());
+    result.extend_from_slice(&jpeg[pos..]);
+    result
+}
+
+/// Embed the xmp packet `xml` in `webp`, an image of `width` x `height`.
+///
+/// Metadata requires the extended format, so a VP8X chunk is added
+/// first unless there already is one, and the packet is put in an
+/// XMP chunk last.
+pub fn embed_in_webp(
+    webp: Vec<u8>,
+    width: u32,
+    height: u32,
+    xml: &str,
+) -> Vec<u8> {
+    const XMP_FLAG: u8 = 0x04;
+    if webp.len() < 20 || &webp[..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        warn!("Can not embed xmp in a non-webp image");
+        return webp;
+    }
+    let mut result = Vec::with_capacity(webp.len() + 30 + xml.len());
+    result.extend_from_slice(&webp[..12]);
+    if &webp[12..16] == b"VP8X" {
+        result.extend_from_slice(&webp[12..]);
+        result[20] |= XMP_FLAG;
+    } else {
+        let mut vp8x = vec![XMP_FLAG, 0, 0, 0];
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        add_riff_chunk(&mut result, b"VP8X", &vp8x);
+        result.extend_from_slice(&webp[12..]);
+    }
+    add_riff_chunk(&mut result, b"XMP ", xml.as_bytes());
+    let size = (result.len() - 8) as u32;
+    result[4..8].copy_from_slice(&size.to_le_bytes());
+    result
+}
+
+/// Add a RIFF chunk, padded to even length.
+fn add_riff_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Get the xmp packet from the APP1 segment of a jpeg file.
+///
+/// Returns None for files that are not jpeg.
+fn embedded_xmp(path: &Path) -> Result<Option<String>, Error> {
+    const HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+    let mut file = BufReader::new(
+        File::open(path).map_err(|e| Error::in_file(&e, path))?,
+    );
+    let mut marker = [0; 2];
+    if file.read_exact(&mut marker).is_err() || marker != [0xFF, 0xD8] {
+        return Ok(None);
+    }
+    loop {
+        let mut head = [0; 4];
+        if file.read_exact(&mut head).is_err() || head[0] != 0xFF {
+            return Ok(None);
+        }
+        // Image data starts at start of scan, no metadata after that.
+        if head[1] == 0xDA {
+            return Ok(None);
+        }
+        let len = usize::from(u16::from_be_bytes([head[2], head[3]]));
+        let mut segment = vec![0; len.saturating_sub(2)];
+        file.read_exact(&mut segment)
+            .map_err(|e| Error::in_file(&e, path))?;
+        if head[1] == 0xE1 && segment.starts_with(HEADER) {
+            let xml = String::from_utf8_lossy(&segment[HEADER.len()..]);
+            return Ok(Some(xml.into_owned()));
+        }
+    }
+}
+
+fn list_items(node: Node) -> Vec<String> {
+    node.descendants()
+        .filter(|n| n.has_tag_name((RDF, "li")))
+        .filter_map(|n| n.text())
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn text(value: &str) -> Option<String> {
+    Some(value.trim())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Parse a gps coordinate, like "59,19.7172N" or "59,19,43.0N".
+///
+/// The coordinate is negative if its reference is `negative`.
+fn parse_gps(value: &str, negative: char, path: &Path) -> Option<f64> {
+    let value = value.trim();
+    let reference = value.chars().last()?;
+    let mut degrees = 0.;
+    for (part, scale) in value[..value.len() - reference.len_utf8()]
+        .split(',')
+        .zip(&[1., 60., 3600.])
+    {
+        match part.parse::<f64>() {
+            Ok(part) => degrees += part / scale,
+            Err(e) => {
+                warn!("Bad xmp gps value {:?} in {:?}: {}", value, path, e);
+                return None;
+            }
+        }
+    }
+    if reference.eq_ignore_ascii_case(&negative) {
+        Some(-degrees)
+    } else {
+        Some(degrees)
+    }
+}
+
+/// Format a gps coordinate as degrees and decimal minutes.
+fn format_gps(value: f64, positive: char, negative: char) -> String {
+    let reference = if value < 0. { negative } else { positive };
+    let value = value.abs();
+    let degrees = value.trunc();
+    format!("{},{:.6}{}", degrees, (value - degrees) * 60., reference)
+}
+
+fn parse_rating(value: &str, path: &Path) -> Option<i8> {
+    match value.trim().parse::<f32>() {
+        Ok(r) => Some(r.round().clamp(-1., 5.) as i8),
+        Err(e) => {
+            warn!("Bad xmp rating {:?} in {:?}: {}", value, path, e);
+            None
+        }
+    }
+}
+
+#[test]
+fn parse_darktable_sidecar() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="XMP Core 4.4.0-Exiv2">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:Iptc4xmpExt="http://iptc.org/std/Iptc4xmpExt/2008-02-29/"
+   xmp:Rating="4">
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>sunset</rdf:li>
+     <rdf:li>beach</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+   <Iptc4xmpExt:PersonInImage>
+    <rdf:Bag>
+     <rdf:li>Kim Doe</rdf:li>
+    </rdf:Bag>
+   </Iptc4xmpExt:PersonInImage>
+   <dc:creator>
+    <rdf:Seq>
+     <rdf:li>Pat Roe</rdf:li>
+    </rdf:Seq>
+   </dc:creator>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+    let xmp = XmpData::parse(xml, Path::new("test.xmp")).unwrap();
+    assert_eq!(
+        xmp,
+        XmpData {
+            keywords: vec!["sunset".into(), "beach".into()],
+            people: vec!["Kim Doe".into()],
+            rating: Some(4),
+            creator: Some("Pat Roe".into()),
+            ..XmpData::default()
+        }
+    );
+    assert_eq!(xmp.grade(), Some(80));
+}
+
+#[test]
+fn parse_rating_element() {
+    let xml = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description xmlns:xmp="http://ns.adobe.com/xap/1.0/">
+   <xmp:Rating>-1</xmp:Rating>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+    let xmp = XmpData::parse(xml, Path::new("test.xmp")).unwrap();
+    assert_eq!(xmp.rating, Some(-1));
+    assert_eq!(xmp.grade(), Some(0));
+}
+
+#[test]
+fn merge_prefers_self() {
+    let sidecar = XmpData {
+        keywords: vec!["beach".into()],
+        rating: Some(4),
+        position: Some((59.3, 18.1)),
+        city: Some("Stockholm".into()),
+        ..XmpData::default()
+    };
+    let embedded = XmpData {
+        keywords: vec!["sunset".into(), "beach".into()],
+        people: vec!["Kim Doe".into()],
+        rating: Some(2),
+        position: Some((57.7, 11.9)),
+        location: Some("Kungsträdgården".into()),
+        city: Some("Göteborg".into()),
+        state: Some("Stockholms län".into()),
+        country: Some("Sverige".into()),
+        description: Some("Sunset".into()),
+        ..XmpData::default()
+    };
+    assert_eq!(
+        sidecar.merge(embedded),
+        XmpData {
+            keywords: vec!["beach".into(), "sunset".into()],
+            people: vec!["Kim Doe".into()],
+            rating: Some(4),
+            position: Some((59.3, 18.1)),
+            location: Some("Kungsträdgården".into()),
+            city: Some("Stockholm".into()),
+            state: Some("Stockholms län".into()),
+            country: Some("Sverige".into()),
+            description: Some("Sunset".into()),
+            ..XmpData::default()
+        }
+    );
+}
+
+#[test]
+fn write_and_parse() {
+    let mut xmp = XmpData {
+        keywords: vec!["fish & chips".into(), "<beach>".into()],
+        people: vec!["Kim \"The Fish\" Doe".into()],
+        position: Some((59.328_67, -18.071_94)),
+        location: Some("Kungsträdgården".into()),
+        city: Some("Stockholm".into()),
+        state: None,
+        country: Some("Sverige".into()),
+        creator: Some("Kim Doe".into()),
+        rights: Some("© 2021 Kim Doe".into()),
+        date_created: Some("2021-05-16T14:30:00+02:00".into()),
+        description: Some("Kim Doe, #fish, Kungsträdgården".into()),
+        ..XmpData::default()
+    };
+    xmp.set_grade(Some(55));
+    assert_eq!(xmp.rating, Some(3));
+    let parsed = XmpData::parse(&xmp.to_xml(), Path::new("test.xmp"));
+    let mut parsed = parsed.unwrap();
+    let (lat, long) = parsed.position.take().unwrap();
+    assert!((lat - 59.328_67).abs() < 1e-6, "lat {}", lat);
+    assert!((long + 18.071_94).abs() < 1e-6, "long {}", long);
+    xmp.position = None;
+    assert_eq!(parsed, xmp);
+}
+
+#[test]
+fn embed_and_read() {
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    let mut jpeg = Vec::new();
+    DynamicImage::ImageRgb8(RgbImage::new(8, 8))
+        .write_to(&mut jpeg, ImageFormat::Jpeg)
+        .unwrap();
+    let xmp = XmpData {
+        creator: Some("Kim Doe".into()),
+        date_created: Some("2021-05-16T14:30:00".into()),
+        ..XmpData::default()
+    };
+    let jpeg = embed_in_jpeg(jpeg, &xmp.to_xml());
+    image::load_from_memory(&jpeg).unwrap();
+    let path = std::env::temp_dir()
+        .join(format!("rphotos-xmp-test-{}.jpg", std::process::id()));
+    fs::write(&path, &jpeg).unwrap();
+    let read = XmpData::read_for(&path);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(read.unwrap(), Some(xmp));
+}
+
+#[test]
+fn embed_in_webp_chunks() {
+    let rgb = [128; 40 * 24 * 3];
+    let webp = webp::Encoder::from_rgb(&rgb, 40, 24).encode(80.).to_vec();
+    let data = embed_in_webp(webp, 40, 24, "<x/>");
+    assert_eq!(&data[..4], b"RIFF");
+    assert_eq!(data[4..8], ((data.len() - 8) as u32).to_le_bytes());
+    let mut chunks = Vec::new();
+    let mut rest = &data[12..];
+    while rest.len() >= 8 {
+        let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]);
+        let (chunk, tail) = rest[8..].split_at(len as usize);
+        chunks.push((&rest[..4], chunk));
+        rest = &tail[(len % 2) as usize..];
+    }
+    assert!(rest.is_empty());
+    let names = chunks.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    assert_eq!(names, [&b"VP8X"[..], b"VP8 ", b"XMP "]);
+    assert_eq!(chunks[0].1, [4, 0, 0, 0, 39, 0, 0, 23, 0, 0]);
+    assert_eq!(chunks[2].1, b"<x/>");
+}